@@ -8,12 +8,14 @@
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
 //!
-//! The build script also sets the linker flags to tell it which link script to use.
+//! The build script also sets the linker flags to tell it which link script to use,
+//! and bakes the commit being built into the firmware as GIT_HASH.
 
 use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
+use std::process::Command;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -43,4 +45,29 @@ fn main() {
     // Set the linker script to the one provided by cortex-m-rt.
     //println!("cargo:-Zbuild-std=none");
     println!("cargo:rustc-link-arg=-Tlink.x");
+
+    // Report the commit in the INFO command. Builds that can't see the repository,
+    // like the Docker one, can pass it in through GIT_HASH instead.
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    let git_hash = env::var("GIT_HASH").ok().or_else(|| git(&["rev-parse", "HEAD"])).unwrap_or_default();
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+
+    // Rebuild when a commit is made or the branch changes
+    if let Some(head) = git(&["rev-parse", "--git-path", "HEAD"]) {
+        println!("cargo:rerun-if-changed={}", head);
+    }
+    if let Some(branch) = git(&["symbolic-ref", "-q", "HEAD"]).and_then(|branch| git(&["rev-parse", "--git-path", &branch])) {
+        println!("cargo:rerun-if-changed={}", branch);
+    }
+}
+
+/// Runs git in the crate directory
+/// @param args The arguments to git
+/// @return The trimmed output, or nothing if git failed
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
@@ -0,0 +1,282 @@
+//! Runs the decoder's command loop on the host, so the TV-side tools can talk to it without a board.
+//! It loads the keys.bin, emergency.bin and public.bin artifacts produced by build.py, keeps the
+//! decoder's flash in an image file, and serves the `%` protocol over a TCP port or a pseudo-terminal.
+
+use ed25519_dalek::VerifyingKey;
+use spark_ectf::command::{Commands, Context};
+use spark_ectf::console::{read_resp, Session};
+use spark_ectf::host::{ConsoleClosed, FileFlash, HostDelay, HostRng, Pty, StreamConsole};
+use spark_ectf::protocol::{LinkStats, MessageHeader, ACK, HEADER_SIZE};
+use spark_ectf::replay::{ReplayGuard, ReplayPolicy};
+use spark_ectf::subscription::Subscription;
+use spark_ectf::{load_subscriptions, parse_decoder_id, Board, DeviceInfo, DeviceKeys};
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::process::exit;
+use std::thread::sleep;
+use std::time::Duration;
+
+const USAGE: &str = "usage: decoder-sim [--artifacts DIR] [--channels 1,3,7] [--decoder-id ID] [--flash FILE] \
+[--max-frame-size N] [--replay-policy global|channel] (--tcp ADDR | --pty)
+
+  --artifacts DIR       Directory holding keys.bin, emergency.bin and public.bin (default: decoder/src)
+  --channels LIST       The CHANNELS the decoder was built with, without the emergency channel
+  --decoder-id ID       The DECODER_ID the decoder was built with (default: 0xdeadbeef)
+  --flash FILE          Flash image to keep subscriptions in (default: decoder-flash.bin)
+  --max-frame-size N    MAX_FRAME_SIZE the decoder was built with (default: 64)
+  --replay-policy NAME  REPLAY_POLICY the decoder was built with (default: global)
+  --tcp ADDR            Serve one connection at a time on a TCP address, like 127.0.0.1:2025
+  --pty                 Serve on a new pseudo-terminal, whose path is printed on startup";
+
+/// Where the simulated decoder listens for the TV
+enum Link {
+    Tcp(String),
+    Pty,
+}
+
+struct Args {
+    artifacts: PathBuf,
+    channels: [u32; 17],
+    decoder_id: u32,
+    flash: PathBuf,
+    max_frame_size: String,
+    policy: ReplayPolicy,
+    link: Link,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+        let mut artifacts = PathBuf::from("decoder/src");
+        let mut channels = [0u32; 17];
+        let mut decoder_id = 0xdead_beef;
+        let mut flash = PathBuf::from("decoder-flash.bin");
+        let mut max_frame_size = String::from("64");
+        let mut policy = ReplayPolicy::StrictGlobal;
+        let mut link = None;
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+            match arg.as_str() {
+                "--artifacts" => artifacts = PathBuf::from(value()?),
+                "--channels" => channels = parse_channels(&value()?)?,
+                "--decoder-id" => {
+                    decoder_id = parse_decoder_id(&value()?)
+                        .ok_or("--decoder-id must be a 32-bit number, in hex with a 0x prefix or in decimal")?
+                }
+                "--flash" => flash = PathBuf::from(value()?),
+                "--max-frame-size" => max_frame_size = value()?,
+                "--replay-policy" => {
+                    policy = match value()?.as_str() {
+                        "global" => ReplayPolicy::StrictGlobal,
+                        "channel" => ReplayPolicy::StrictPerChannel,
+                        other => return Err(format!("unknown replay policy {other}")),
+                    }
+                }
+                "--tcp" => link = Some(Link::Tcp(value()?)),
+                "--pty" => link = Some(Link::Pty),
+                other => return Err(format!("unknown argument {other}")),
+            }
+        }
+        let link = link.ok_or("one of --tcp or --pty is required")?;
+        Ok(Args { artifacts, channels, decoder_id, flash, max_frame_size, policy, link })
+    }
+}
+
+/// Parses a channel list the same way the firmware parses CHANNELS
+fn parse_channels(list: &str) -> Result<[u32; 17], String> {
+    let mut ret = [0u32; 17];
+    for (i, channel) in list.split(',').filter(|c| !c.is_empty()).enumerate() {
+        let channel = channel.trim().parse::<u32>().map_err(|_| format!("bad channel {channel}"))?;
+        if i + 1 < ret.len() {
+            ret[i + 1] = channel;
+        }
+    }
+    Ok(ret)
+}
+
+/// Reads one of the build artifacts, leaking it since the decoder keeps it for its whole run
+fn load_artifact(args: &Args, name: &str) -> &'static [u8] {
+    let path = args.artifacts.join(name);
+    match std::fs::read(&path) {
+        Ok(bytes) => Box::leak(bytes.into_boxed_slice()),
+        Err(err) => {
+            eprintln!("couldn't read {}: {err}", path.display());
+            exit(1);
+        }
+    }
+}
+
+/// Everything that outlives a single connection, just like it outlives a command on the board
+struct Decoder {
+    flash: FileFlash,
+    trng: HostRng,
+    delay: HostDelay,
+    keys: DeviceKeys,
+    info: DeviceInfo,
+    subscriptions: [Option<Subscription>; 9],
+    replay: ReplayGuard,
+    verifier: VerifyingKey,
+    /// The link counters, which count from when the simulator started rather than from each connection
+    stats: LinkStats,
+}
+
+impl Decoder {
+    /// Runs the command loop over a stream until it is closed
+    fn serve<S: Read + Write>(&mut self, stream: S) {
+        let mut board = Board {
+            console: StreamConsole::new(stream),
+            flash: &mut self.flash,
+            trng: &mut self.trng,
+            delay: &mut self.delay,
+            keys: self.keys,
+            info: self.info,
+        };
+        // Every connection starts out in lock-step, like a freshly reset board
+        let mut session = Session { stats: self.stats, ..Session::default() };
+        let mut ctx = Context {
+            board: &mut board,
+            subscriptions: &mut self.subscriptions,
+            replay: &mut self.replay,
+            verifier: self.verifier,
+            session: &mut session,
+        };
+        let commands = Commands::default();
+        let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| loop {
+            read_resp(&mut ctx, &commands);
+        }));
+        self.stats = session.stats;
+        if !payload.is::<ConsoleClosed>() {
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{err}\n\n{USAGE}");
+        exit(2);
+    });
+
+    // A closed console is how every connection ends, so it isn't worth a backtrace
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if !info.payload().is::<ConsoleClosed>() {
+            default_hook(info);
+        }
+    }));
+
+    let keys = DeviceKeys {
+        keys: load_artifact(&args, "keys.bin"),
+        emergency: load_artifact(&args, "emergency.bin"),
+        channels: args.channels,
+    };
+    let public: [u8; 32] = load_artifact(&args, "public.bin").try_into().unwrap_or_else(|_| {
+        eprintln!("public.bin must hold a 32-byte Ed25519 key");
+        exit(1);
+    });
+    let verifier = VerifyingKey::from_bytes(&public).unwrap_or_else(|err| {
+        eprintln!("bad public.bin: {err}");
+        exit(1);
+    });
+    let info = DeviceInfo::parse(args.decoder_id, env!("CARGO_PKG_VERSION"), option_env!("GIT_HASH").unwrap_or(""),
+        &args.max_frame_size);
+    let mut flash = FileFlash::open(&args.flash).unwrap_or_else(|err| {
+        eprintln!("couldn't open {}: {err}", args.flash.display());
+        exit(1);
+    });
+
+    // Boots the same way the firmware does, reporting anything it prints to stderr
+    let mut boot = Board {
+        console: StreamConsole::new(BootConsole { acked: 0, header: Vec::new(), body: Vec::new() }),
+        flash: &mut flash,
+        trng: HostRng::new(std::process::id()),
+        delay: HostDelay,
+        keys,
+        info,
+    };
+    let mut subscriptions = load_subscriptions(&mut boot);
+    let trng = boot.trng;
+    let replay = ReplayGuard::load(args.policy, &mut flash);
+    for sub in subscriptions.iter_mut().flatten() {
+        replay.restore(sub);
+    }
+    let mut decoder = Decoder { flash, trng, delay: HostDelay, keys, info, subscriptions, replay, verifier, stats: LinkStats::default() };
+
+    match &args.link {
+        Link::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).unwrap_or_else(|err| {
+                eprintln!("couldn't listen on {addr}: {err}");
+                exit(1);
+            });
+            eprintln!("decoder listening on {addr}, which the tools open as socket://{addr}");
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let _ = stream.set_nodelay(true);
+                        // Lets reads time out, like they do on the UART
+                        let _ = stream.set_nonblocking(true);
+                        decoder.serve(stream);
+                    }
+                    Err(err) => eprintln!("connection failed: {err}"),
+                }
+            }
+        }
+        Link::Pty => {
+            let mut pty = Pty::open().unwrap_or_else(|err| {
+                eprintln!("couldn't open a pseudo-terminal: {err}");
+                exit(1);
+            });
+            println!("{}", pty.slave_path());
+            // The master reads fail while no tool has the terminal open, so keep polling for the next one
+            loop {
+                decoder.serve(&mut pty);
+                sleep(Duration::from_millis(50));
+            }
+        }
+    }
+}
+
+/// Stands in for the UART during boot, when no TV is connected yet, acknowledging everything the decoder sends
+/// and printing the body of each message it writes
+struct BootConsole {
+    acked: usize,
+    header: Vec<u8>,
+    body: Vec<u8>,
+}
+
+impl Read for BootConsole {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        for byte in buf.iter_mut() {
+            *byte = ACK[self.acked % ACK.len()];
+            self.acked += 1;
+        }
+        Ok(buf.len())
+    }
+}
+
+impl Write for BootConsole {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for &byte in buf {
+            if self.header.len() < HEADER_SIZE {
+                self.header.push(byte);
+            } else {
+                self.body.push(byte);
+            }
+            let Ok(header) = <[u8; HEADER_SIZE]>::try_from(&self.header[..]) else {
+                continue;
+            };
+            let length = MessageHeader::decode(&header).map_or(0, |header| header.length as usize);
+            if self.body.len() == length {
+                eprintln!("boot: {}", String::from_utf8_lossy(&self.body));
+                self.header.clear();
+                self.body.clear();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,854 @@
+//! The commands the decoder answers, one handler per opcode, in lock-step and, for the few that take it, in streaming mode.
+//! Every handler declares the longest body it takes and whether the integrity check has to pass before it runs,
+//! so the command loop can turn a message away before any of its body is sent.
+//! A new command is added by writing a handler and registering it in a Commands table.
+
+use crate::{check_integrity, get_subscription_for_channel, is_loadable, load_subscription, remove_subscription, verify_subscription, Board,
+    DEVICE_ID_LOC, MAX_PACKET_SIZE, PACKET_OVERHEAD, SIGNATURE_SIZE, SUBSCRIPTION_SIZE};
+use crate::console::{ack, decode_frame, decode_subroutine, device_info, error_body, read_block, read_body, read_body_into, write_comm, write_console,
+    write_err, write_unacked, Session};
+use crate::error::DecoderError;
+use crate::hw::{ByteIo, Delay, Flash, Rng};
+use crate::protocol::{decode_stream, encode_batch_result, encode_stream, BlockReassembler, HelloRecord, LinkMode, MessageHeader, Opcode,
+    BATCH_RESULT_OVERHEAD, FEATURE_BATCH, FEATURE_CRC, FEATURE_STREAM, HEADER_SIZE, HELLO_REQUEST_SIZE, LINK_CRC, LINK_FLAGS, MAX_BATCH, MAX_PING,
+    MAX_STREAM_WINDOW, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, STATS_SIZE, STATUS_OK, STREAM_SIZE};
+use crate::replay::ReplayGuard;
+use crate::subscription::{get_subscriptions, Subscription};
+use crate::subscription_log::SubscriptionLog;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use ed25519_dalek::VerifyingKey;
+
+/// Answers one kind of message
+pub trait CommandHandler<C: ByteIo, F: Flash, R: Rng, D: Delay> {
+    /// Gets the opcode this handler answers
+    /// @return The opcode
+    fn opcode(&self) -> Opcode;
+
+    /// Gets the longest body this command takes
+    /// @return The maximum length of the body
+    fn max_payload(&self) -> usize;
+
+    /// Checks whether the integrity check has to pass before the command runs
+    /// @return Whether the check is run
+    fn needs_integrity(&self) -> bool {
+        false
+    }
+
+    /// Receives the body of the message and answers it.
+    /// The header has already been checked against max_payload, and nothing has been acknowledged yet.
+    /// @param ctx The decoder state the command works on
+    /// @param header The header of the message
+    fn handle(&self, ctx: &mut Context<C, F, R, D>, header: &MessageHeader);
+
+    /// Answers a message received in streaming mode, where nothing is acknowledged in either direction.
+    /// Every message gets exactly one answer, which hands its credit back to the host, so a command that
+    /// isn't taken while streaming is answered with an error.
+    /// @param ctx The decoder state the command works on
+    /// @param body The whole body, or BadLength if it was longer than any packet and was thrown away
+    fn handle_streaming(&self, ctx: &mut Context<C, F, R, D>, body: Result<&[u8], DecoderError>) {
+        let _ = body;
+        write_unacked(&mut ctx.board.console, &error_body(DecoderError::UnknownOpcode), Opcode::Error);
+    }
+}
+
+/// The decoder state a command works on, borrowed from the command loop for one message
+pub struct Context<'a, C, F, R, D> {
+    /// The board, with the console the TV talks through
+    pub board: &'a mut Board<C, F, R, D>,
+    /// The subscription list
+    pub subscriptions: &'a mut [Option<Subscription>; 9],
+    /// The decoder-wide replay protection state
+    pub replay: &'a mut ReplayGuard,
+    /// The verifying key for decoded frames
+    pub verifier: VerifyingKey,
+    /// The state of the link to the TV
+    pub session: &'a mut Session,
+}
+
+/// The number of entries in a Commands table, one for each capital letter an opcode can be
+const COMMAND_SLOTS: usize = 26;
+
+/// The handlers the command loop dispatches to, looked up by opcode
+pub struct Commands<'h, C, F, R, D> {
+    handlers: [Option<&'h dyn CommandHandler<C, F, R, D>>; COMMAND_SLOTS],
+}
+
+impl<'h, C: ByteIo, F: Flash, R: Rng, D: Delay> Commands<'h, C, F, R, D> {
+    /// Creates a table that answers nothing
+    pub fn empty() -> Commands<'h, C, F, R, D> {
+        Commands { handlers: [None; COMMAND_SLOTS] }
+    }
+
+    /// Adds a handler, replacing any earlier one for the same opcode
+    /// @param handler The handler to add
+    pub fn register(&mut self, handler: &'h dyn CommandHandler<C, F, R, D>) {
+        self.handlers[slot(handler.opcode())] = Some(handler);
+    }
+
+    /// Gets the handler for an opcode
+    /// @param opcode The opcode of a message
+    /// @return The handler, or nothing if no command has that opcode
+    pub fn find(&self, opcode: Opcode) -> Option<&'h dyn CommandHandler<C, F, R, D>> {
+        self.handlers[slot(opcode)]
+    }
+}
+
+impl<C: ByteIo, F: Flash, R: Rng, D: Delay> Default for Commands<'_, C, F, R, D> {
+    /// Creates a table with every command the decoder ships with
+    fn default() -> Self {
+        let mut commands = Commands::empty();
+        commands.register(&HelloCommand(FEATURE_BATCH | FEATURE_CRC | FEATURE_STREAM));
+        commands.register(&PingCommand);
+        commands.register(&ListCommand);
+        commands.register(&SubscribeCommand);
+        commands.register(&InfoCommand);
+        commands.register(&StreamCommand);
+        commands.register(&OptionsCommand);
+        commands.register(&UnsubscribeCommand);
+        commands.register(&DecodeCommand);
+        commands.register(&BatchDecodeCommand);
+        // A late ACK or NAK from a transfer that was already given up on is dropped
+        commands.register(&IgnoreCommand(Opcode::Ack));
+        commands.register(&IgnoreCommand(Opcode::Nak));
+        commands
+    }
+}
+
+/// Gets the table entry for an opcode
+/// @param opcode The opcode
+/// @return The index into the table
+fn slot(opcode: Opcode) -> usize {
+    (opcode.as_byte() - b'A') as usize
+}
+
+/// Tells the host which versions of the protocol the decoder speaks, holding the FEATURE bits it reports
+pub struct HelloCommand(pub u32);
+
+impl<C: ByteIo, F: Flash, R: Rng, D: Delay> CommandHandler<C, F, R, D> for HelloCommand {
+    fn opcode(&self) -> Opcode {
+        Opcode::Hello
+    }
+
+    fn max_payload(&self) -> usize {
+        HELLO_REQUEST_SIZE
+    }
+
+    fn handle(&self, ctx: &mut Context<C, F, R, D>, header: &MessageHeader) {
+        let Context { board, session, .. } = ctx;
+        let byte_list = match read_body(&mut board.console, &mut board.delay, session, header) {
+            Ok(byte_list) => byte_list,
+            Err(err) => {
+                write_err(&mut board.console, &mut board.delay, err);
+                return;
+            }
+        };
+        let Ok(version) = <[u8; HELLO_REQUEST_SIZE]>::try_from(&byte_list[..]) else {
+            write_err(&mut board.console, &mut board.delay, DecoderError::BadLength);
+            return;
+        };
+        let hello = HelloRecord { min_version: MIN_PROTOCOL_VERSION, max_version: PROTOCOL_VERSION, features: self.0 };
+        if !hello.supports(u16::from_le_bytes(version)) {
+            // The error only carries its code, so the range goes out first for whoever is reading the log
+            write_console(&mut board.console, format!("Speaks protocol versions {} to {}", MIN_PROTOCOL_VERSION, PROTOCOL_VERSION).as_bytes());
+            write_err(&mut board.console, &mut board.delay, DecoderError::UnsupportedVersion);
+            return;
+        }
+        write_comm(&mut board.console, &mut board.delay, &hello.encode(), Opcode::Hello);
+    }
+}
+
+/// Echoes its payload back after the link counters, so the host can tell a live decoder from a stalled one
+pub struct PingCommand;
+
+impl<C: ByteIo, F: Flash, R: Rng, D: Delay> CommandHandler<C, F, R, D> for PingCommand {
+    fn opcode(&self) -> Opcode {
+        Opcode::Ping
+    }
+
+    fn max_payload(&self) -> usize {
+        MAX_PING
+    }
+
+    fn handle(&self, ctx: &mut Context<C, F, R, D>, header: &MessageHeader) {
+        let Context { board, session, .. } = ctx;
+        let mut ret = [0u8; STATS_SIZE + MAX_PING];
+        if let Err(err) = read_body_into(&mut board.console, &mut board.delay, session, header, &mut ret[STATS_SIZE..]) {
+            write_err(&mut board.console, &mut board.delay, err);
+            return;
+        }
+        ret[..STATS_SIZE].copy_from_slice(&session.stats.encode());
+        write_comm(&mut board.console, &mut board.delay, &ret[..STATS_SIZE + header.length as usize], Opcode::Ping);
+    }
+}
+
+/// Lists the subscriptions
+pub struct ListCommand;
+
+impl<C: ByteIo, F: Flash, R: Rng, D: Delay> CommandHandler<C, F, R, D> for ListCommand {
+    fn opcode(&self) -> Opcode {
+        Opcode::List
+    }
+
+    fn max_payload(&self) -> usize {
+        0
+    }
+
+    // Delays to avoid side channel attacks
+    fn needs_integrity(&self) -> bool {
+        true
+    }
+
+    fn handle(&self, ctx: &mut Context<C, F, R, D>, _header: &MessageHeader) {
+        let Context { board, subscriptions, .. } = ctx;
+        ack(&mut board.console);
+        // Responds to the list command by getting the subscriptions...
+        let subscriptions = get_subscriptions(subscriptions);
+
+        // Allocating the return space...
+        let mut ret = vec![0u8; 4usize + subscriptions.len()*20usize];
+        ret[0..4].copy_from_slice(bytemuck::bytes_of(&(subscriptions.len() as u32)));
+
+        // Casts parts of the subscription data to the listing to put it in the right format
+        for (i, sub) in subscriptions.iter().enumerate() {
+            ret[i*20usize+4..i*20usize+8].copy_from_slice(bytemuck::bytes_of(&(sub.channel)));
+            ret[i*20usize+8..i*20usize+16].copy_from_slice(bytemuck::bytes_of(&(sub.start)));
+            ret[i*20usize+16..i*20usize+24].copy_from_slice(bytemuck::bytes_of(&(sub.end)));
+        }
+        // Send the listing information
+        write_comm(&mut board.console, &mut board.delay, &ret, Opcode::List);
+    }
+}
+
+/// Stores a subscription in the slot for its channel
+pub struct SubscribeCommand;
+
+impl<C: ByteIo, F: Flash, R: Rng, D: Delay> CommandHandler<C, F, R, D> for SubscribeCommand {
+    fn opcode(&self) -> Opcode {
+        Opcode::Subscribe
+    }
+
+    fn max_payload(&self) -> usize {
+        SUBSCRIPTION_SIZE + SIGNATURE_SIZE
+    }
+
+    fn needs_integrity(&self) -> bool {
+        true
+    }
+
+    fn handle(&self, ctx: &mut Context<C, F, R, D>, header: &MessageHeader) {
+        let Context { board, subscriptions, replay, verifier, session } = ctx;
+        // The body has to be a whole subscription and its signature
+        if header.length as usize != SUBSCRIPTION_SIZE + SIGNATURE_SIZE {
+            write_err(&mut board.console, &mut board.delay, DecoderError::BadLength);
+            return;
+        }
+        // Receives the whole subscription before touching the flash, so a transfer that stops short
+        // leaves the old subscription in place rather than an erased slot
+        let mut byte_list = [0u8; SUBSCRIPTION_SIZE + SIGNATURE_SIZE];
+        if let Err(err) = read_body_into(&mut board.console, &mut board.delay, session, header, &mut byte_list) {
+            write_err(&mut board.console, &mut board.delay, err);
+            return;
+        }
+        // Nothing about a subscription is trusted until its signature checks out
+        if let Err(err) = verify_subscription(board, *verifier, &byte_list) {
+            write_err(&mut board.console, &mut board.delay, err);
+            return;
+        }
+
+        // A subscription made for another decoder would only decrypt to garbage keys here
+        let device_id = u32::from_be_bytes(byte_list[DEVICE_ID_LOC..DEVICE_ID_LOC + 4].try_into().unwrap());
+        if device_id != board.info.decoder_id {
+            write_err(&mut board.console, &mut board.delay, DecoderError::WrongDevice);
+            return;
+        }
+
+        // Casts the first 4 bytes to the channel value
+        let channel_id = u32::from_be_bytes(byte_list[0..4].try_into().unwrap());
+        if channel_id == 0 {
+            write_err(&mut board.console, &mut board.delay, DecoderError::EmergencySubscription);
+            return;
+        }
+        // A subscription that couldn't be loaded back must never take over from the one already there
+        if !is_loadable(&byte_list) {
+            write_err(&mut board.console, &mut board.delay, DecoderError::SubscriptionLoad);
+            return;
+        }
+
+        // Turns the channel ID into a possible index, which only fails once every slot holds another channel
+        let maybe_channel = get_subscription_for_channel(channel_id, subscriptions);
+        if maybe_channel.is_none() {
+            write_err(&mut board.console, &mut board.delay, DecoderError::TableFull);
+            return;
+        }
+        let channel = maybe_channel.unwrap();
+
+        // This is a good example of the reliability testing we're doing.
+        if let Err(err) = check_integrity(board) {
+            write_err(&mut board.console, &mut board.delay, err);
+            return;
+        }
+        // Appends the subscription to the log, which may move the other subscriptions while making room.
+        // The previous subscription stays in place until the new one is committed.
+        let mut log = SubscriptionLog::load(&mut board.flash, board.keys.storage_key());
+        let address = match log.write(&mut board.flash, channel_id, &byte_list[..SUBSCRIPTION_SIZE]) {
+            Ok(address) => address,
+            Err(err) => {
+                write_err(&mut board.console, &mut board.delay, err);
+                return;
+            }
+        };
+        log.relocate(subscriptions);
+
+        // Load subscription and send confirmation/error
+        let Some(mut sub) = load_subscription(board, address) else {
+            write_err(&mut board.console, &mut board.delay, DecoderError::SubscriptionLoad);
+            return;
+        };
+        // Renewing a channel must not reopen the window for frames it already accepted
+        replay.restore(&mut sub);
+        subscriptions[channel as usize] = Some(sub);
+        write_comm(&mut board.console, &mut board.delay, b"", Opcode::Subscribe);
+    }
+}
+
+/// Describes the decoder and what it can do
+pub struct InfoCommand;
+
+impl<C: ByteIo, F: Flash, R: Rng, D: Delay> CommandHandler<C, F, R, D> for InfoCommand {
+    fn opcode(&self) -> Opcode {
+        Opcode::Info
+    }
+
+    fn max_payload(&self) -> usize {
+        0
+    }
+
+    fn handle(&self, ctx: &mut Context<C, F, R, D>, _header: &MessageHeader) {
+        let Context { board, subscriptions, .. } = ctx;
+        ack(&mut board.console);
+        // The log is read again to find the subscriptions that were dropped as corrupt, which the table doesn't keep
+        let log = SubscriptionLog::load(&mut board.flash, board.keys.storage_key());
+        let info = device_info(board, subscriptions, log.corrupt()).encode();
+        write_comm(&mut board.console, &mut board.delay, &info, Opcode::Info);
+    }
+}
+
+/// Switches the link between lock-step and streaming mode
+pub struct StreamCommand;
+
+impl<C: ByteIo, F: Flash, R: Rng, D: Delay> CommandHandler<C, F, R, D> for StreamCommand {
+    fn opcode(&self) -> Opcode {
+        Opcode::Stream
+    }
+
+    fn max_payload(&self) -> usize {
+        STREAM_SIZE
+    }
+
+    fn handle(&self, ctx: &mut Context<C, F, R, D>, header: &MessageHeader) {
+        let Context { board, session, .. } = ctx;
+        let byte_list = match read_body(&mut board.console, &mut board.delay, session, header) {
+            Ok(byte_list) => byte_list,
+            Err(err) => {
+                write_err(&mut board.console, &mut board.delay, err);
+                return;
+            }
+        };
+        match decode_stream(&byte_list) {
+            Some((LinkMode::Streaming, requested)) => {
+                // The answer is still sent in lock-step, and streaming starts with the next message
+                let packet_size = PACKET_OVERHEAD + board.info.max_frame_size as usize;
+                session.window = stream_window(&board.console, packet_size, requested);
+                write_comm(&mut board.console, &mut board.delay, &encode_stream(LinkMode::Streaming, session.window), Opcode::Stream);
+                session.mode = LinkMode::Streaming;
+            }
+            Some((LinkMode::LockStep, _)) => write_comm(&mut board.console, &mut board.delay, &encode_stream(LinkMode::LockStep, 0), Opcode::Stream),
+            None => write_err(&mut board.console, &mut board.delay, DecoderError::BadLength),
+        }
+    }
+
+    fn handle_streaming(&self, ctx: &mut Context<C, F, R, D>, body: Result<&[u8], DecoderError>) {
+        let Context { board, session, .. } = ctx;
+        match body.ok().and_then(decode_stream) {
+            // The answer is the last message without ACKs
+            Some((LinkMode::LockStep, _)) => {
+                session.mode = LinkMode::LockStep;
+                session.window = 0;
+                write_unacked(&mut board.console, &encode_stream(LinkMode::LockStep, 0), Opcode::Stream);
+            }
+            Some((LinkMode::Streaming, _)) => write_unacked(&mut board.console, &encode_stream(LinkMode::Streaming, session.window), Opcode::Stream),
+            None => write_unacked(&mut board.console, &error_body(DecoderError::BadLength), Opcode::Error),
+        }
+    }
+}
+
+/// Works out the streaming window to grant, which is limited by how many packets the console can hold while a frame is decoded
+/// @param console: The console the TV talks through.
+/// @param packet_size: The size of the largest frame packet the decoder takes.
+/// @param requested: The window the host asked for.
+/// @return The window to grant.
+fn stream_window<C: ByteIo>(console: &C, packet_size: usize, requested: u16) -> u16 {
+    let buffered = 1 + console.rx_capacity() / (HEADER_SIZE + packet_size);
+    requested.min(MAX_STREAM_WINDOW).min(buffered.min(MAX_STREAM_WINDOW as usize) as u16).max(1)
+}
+
+/// Sets the link options, which apply from the next message on
+pub struct OptionsCommand;
+
+impl<C: ByteIo, F: Flash, R: Rng, D: Delay> CommandHandler<C, F, R, D> for OptionsCommand {
+    fn opcode(&self) -> Opcode {
+        Opcode::Options
+    }
+
+    fn max_payload(&self) -> usize {
+        1
+    }
+
+    fn handle(&self, ctx: &mut Context<C, F, R, D>, header: &MessageHeader) {
+        let Context { board, session, .. } = ctx;
+        let byte_list = match read_body(&mut board.console, &mut board.delay, session, header) {
+            Ok(byte_list) => byte_list,
+            Err(err) => {
+                write_err(&mut board.console, &mut board.delay, err);
+                return;
+            }
+        };
+        let [flags] = byte_list[..] else {
+            write_err(&mut board.console, &mut board.delay, DecoderError::BadLength);
+            return;
+        };
+        session.crc = flags & LINK_CRC != 0;
+        write_comm(&mut board.console, &mut board.delay, &[flags & LINK_FLAGS], Opcode::Options);
+    }
+}
+
+/// Removes the subscription to a channel
+pub struct UnsubscribeCommand;
+
+impl<C: ByteIo, F: Flash, R: Rng, D: Delay> CommandHandler<C, F, R, D> for UnsubscribeCommand {
+    fn opcode(&self) -> Opcode {
+        Opcode::Unsubscribe
+    }
+
+    // The body is just the channel ID, big-endian like the channel in a subscription
+    fn max_payload(&self) -> usize {
+        4
+    }
+
+    // remove_subscription runs the integrity check itself
+    fn handle(&self, ctx: &mut Context<C, F, R, D>, header: &MessageHeader) {
+        let Context { board, subscriptions, session, .. } = ctx;
+        let byte_list = match read_body(&mut board.console, &mut board.delay, session, header) {
+            Ok(byte_list) => byte_list,
+            Err(err) => {
+                write_err(&mut board.console, &mut board.delay, err);
+                return;
+            }
+        };
+        let Ok(channel_id) = <[u8; 4]>::try_from(&byte_list[..]) else {
+            write_err(&mut board.console, &mut board.delay, DecoderError::BadLength);
+            return;
+        };
+        match remove_subscription(board, subscriptions, u32::from_be_bytes(channel_id)) {
+            Ok(()) => write_comm(&mut board.console, &mut board.delay, b"", Opcode::Unsubscribe),
+            Err(err) => write_err(&mut board.console, &mut board.delay, err),
+        }
+    }
+}
+
+/// Decodes a single frame
+pub struct DecodeCommand;
+
+impl<C: ByteIo, F: Flash, R: Rng, D: Delay> CommandHandler<C, F, R, D> for DecodeCommand {
+    fn opcode(&self) -> Opcode {
+        Opcode::Decode
+    }
+
+    fn max_payload(&self) -> usize {
+        MAX_PACKET_SIZE
+    }
+
+    // decode_subroutine runs the integrity check itself, once per frame
+    fn handle(&self, ctx: &mut Context<C, F, R, D>, header: &MessageHeader) {
+        let Context { board, subscriptions, replay, verifier, session } = ctx;
+        // The body is exactly one frame packet, which is refused before the first ACK if its frame is longer than this build takes
+        let length = header.length as usize;
+        if length < PACKET_OVERHEAD || length > PACKET_OVERHEAD + board.info.max_frame_size as usize {
+            write_err(&mut board.console, &mut board.delay, DecoderError::BadLength);
+            return;
+        }
+        // Receives bytes
+        let byte_list = match read_body(&mut board.console, &mut board.delay, session, header) {
+            Ok(byte_list) => byte_list,
+            Err(err) => {
+                write_err(&mut board.console, &mut board.delay, err);
+                return;
+            }
+        };
+
+        // Returns the decoded bytes to the TV, or tells it why the frame was rejected
+        match decode_subroutine(board, subscriptions, replay, *verifier, &byte_list) {
+            Ok(value) => write_comm(&mut board.console, &mut board.delay, &value, Opcode::Decode),
+            Err(err) => write_err(&mut board.console, &mut board.delay, err),
+        }
+    }
+
+    // Every frame gets exactly one answer, decoded or not
+    fn handle_streaming(&self, ctx: &mut Context<C, F, R, D>, body: Result<&[u8], DecoderError>) {
+        let Context { board, subscriptions, replay, verifier, .. } = ctx;
+        match body.and_then(|body| decode_subroutine(board, subscriptions, replay, *verifier, body)) {
+            Ok(value) => write_unacked(&mut board.console, &value, Opcode::Decode),
+            Err(err) => write_unacked(&mut board.console, &error_body(err), Opcode::Error),
+        }
+    }
+}
+
+/// Decodes several frames in order, answering with a status and frame for each
+pub struct BatchDecodeCommand;
+
+impl<C: ByteIo, F: Flash, R: Rng, D: Delay> CommandHandler<C, F, R, D> for BatchDecodeCommand {
+    fn opcode(&self) -> Opcode {
+        Opcode::BatchDecode
+    }
+
+    fn max_payload(&self) -> usize {
+        MAX_BATCH * MAX_PACKET_SIZE
+    }
+
+    // decode_frame runs the integrity check itself, once per frame
+    fn handle(&self, ctx: &mut Context<C, F, R, D>, header: &MessageHeader) {
+        let Context { board, subscriptions, replay, verifier, session } = ctx;
+        // The body is up to MAX_BATCH frame packets back to back, each as long as the frame it names,
+        // which are decoded in order as they arrive
+        if (header.length as usize) < PACKET_OVERHEAD {
+            write_err(&mut board.console, &mut board.delay, DecoderError::BadLength);
+            return;
+        }
+        let max_packet = PACKET_OVERHEAD + board.info.max_frame_size as usize;
+        // Reserved up front, since growing it would need the old and the new buffer at once on the firmware's small heap
+        let mut results = Vec::with_capacity(MAX_BATCH * (BATCH_RESULT_OVERHEAD + board.info.max_frame_size as usize));
+        let mut packet = [0u8; MAX_PACKET_SIZE];
+        let mut filled = 0;
+        let mut expected = PACKET_OVERHEAD;
+        let mut decoded = 0;
+        // Once a packet is malformed the rest of the body can't be split into packets, but it is still read
+        // so that the next header is where the host puts it
+        let mut failed = None;
+        let mut body = BlockReassembler::new(header);
+        ack(&mut board.console);
+        loop {
+            let block = match read_block(&mut board.console, &mut board.delay, session, &mut body) {
+                Ok(Some(block)) => block,
+                Ok(None) => break,
+                Err(err) => {
+                    write_err(&mut board.console, &mut board.delay, err);
+                    return;
+                }
+            };
+            if failed.is_none() {
+                for &byte in block.data {
+                    packet[filled] = byte;
+                    filled += 1;
+                    // The size of the packet is known once its frame length, which ends 14 bytes in, has arrived
+                    if filled == 14 {
+                        expected = PACKET_OVERHEAD + u16::from_be_bytes([packet[12], packet[13]]) as usize;
+                        if expected > max_packet || decoded == MAX_BATCH {
+                            failed = Some(DecoderError::BadLength);
+                            break;
+                        }
+                    }
+                    if filled < expected {
+                        continue;
+                    }
+                    // Each packet gets the same checks as a single decode, including the replay rules
+                    let (status, frame) = match decode_frame(board, subscriptions, replay, *verifier, &packet[..filled]) {
+                        Ok(frame) => (STATUS_OK, frame),
+                        Err(err) => (err.code(), Vec::new()),
+                    };
+                    encode_batch_result(status, &frame, &mut results);
+                    decoded += 1;
+                    filled = 0;
+                    expected = PACKET_OVERHEAD;
+                }
+            }
+            ack(&mut board.console);
+        }
+        // The last packet was cut short by the end of the body
+        if failed.is_none() && filled != 0 {
+            failed = Some(DecoderError::BadLength);
+        }
+        // Every frame of the batch is persisted with one flush, before any of them goes out
+        if failed.is_none() {
+            failed = replay.flush(&mut board.flash).err();
+        }
+        match failed {
+            Some(err) => write_err(&mut board.console, &mut board.delay, err),
+            None => write_comm(&mut board.console, &mut board.delay, &results, Opcode::BatchDecode),
+        }
+    }
+}
+
+/// Drops a message that needs no answer
+pub struct IgnoreCommand(pub Opcode);
+
+impl<C: ByteIo, F: Flash, R: Rng, D: Delay> CommandHandler<C, F, R, D> for IgnoreCommand {
+    fn opcode(&self) -> Opcode {
+        self.0
+    }
+
+    fn max_payload(&self) -> usize {
+        0
+    }
+
+    fn handle(&self, _ctx: &mut Context<C, F, R, D>, _header: &MessageHeader) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{error, listing, Secrets, Tv, TvBoard, DECODER_ID};
+    use crate::protocol::{InfoRecord, LinkStats, BLOCK_SIZE, INFO_SIZE, MAX_BATCH_RESPONSE};
+    use crate::replay_log::ReplayLog;
+    use crate::MAX_FRAME_SIZE;
+
+    const FRAME: [u8; 64] = [7; 64];
+
+    /// Builds the answer to BATCH_DECODE from the status and frame of each packet
+    fn batch_results(results: &[(u16, &[u8])]) -> (Opcode, Vec<u8>) {
+        let mut body = Vec::new();
+        for (status, frame) in results {
+            encode_batch_result(*status, frame, &mut body);
+        }
+        (Opcode::BatchDecode, body)
+    }
+
+    /// Boots a decoder built for channels 1 and 3, subscribed to channel 1
+    fn subscribed(secrets: &Secrets) -> (Tv, std::thread::JoinHandle<TvBoard>) {
+        let (mut tv, decoder) = Tv::boot(secrets);
+        assert_eq!(tv.command(Opcode::Subscribe, &secrets.subscription(1, 100, 5000)), (Opcode::Subscribe, Vec::new()));
+        (tv, decoder)
+    }
+
+    #[test]
+    fn batch_answers_each_packet_with_its_status() {
+        let secrets = Secrets::new(&[1, 3]);
+        let (mut tv, decoder) = subscribed(&secrets);
+        let body = [
+            secrets.encode(1, &FRAME, 200),
+            secrets.encode(1, &FRAME, 200),
+            secrets.encode(3, &FRAME, 200),
+            secrets.encode(0, &[9], 50),
+            secrets.encode(1, &FRAME, 201),
+        ].concat();
+        // Most of the packets straddle a block boundary
+        assert!(body.len() > 2 * BLOCK_SIZE);
+        assert_eq!(tv.command(Opcode::BatchDecode, &body), batch_results(&[
+            (STATUS_OK, &FRAME),
+            (DecoderError::Replayed.code(), &[]),
+            (DecoderError::NotSubscribed.code(), &[]),
+            (STATUS_OK, &[9]),
+            (STATUS_OK, &FRAME),
+        ]));
+
+        drop(tv);
+        decoder.join().unwrap();
+    }
+
+    #[test]
+    fn batch_takes_at_most_max_batch_packets() {
+        let secrets = Secrets::new(&[1, 3]);
+        let (mut tv, decoder) = subscribed(&secrets);
+        let packets = |first: u64, count: u64| (first..first + count).map(|timestamp| secrets.encode(1, &FRAME, timestamp)).collect::<Vec<_>>();
+
+        let full = packets(200, MAX_BATCH as u64);
+        let (opcode, body) = tv.command(Opcode::BatchDecode, &full.concat());
+        assert_eq!((opcode, body.len()), (Opcode::BatchDecode, MAX_BATCH * (4 + FRAME.len())));
+
+        // The whole body is still read, so the TV is never left with blocks it can't send
+        assert_eq!(tv.command(Opcode::BatchDecode, &packets(300, MAX_BATCH as u64 + 1).concat()), error(DecoderError::BadLength));
+        assert_eq!(tv.unsent, 0);
+        assert_eq!(tv.command(Opcode::List, b""), listing(&[(1, 100, 5000)]));
+
+        drop(tv);
+        decoder.join().unwrap();
+    }
+
+    #[test]
+    fn batch_answers_a_full_batch_of_the_largest_frames() {
+        let secrets = Secrets::new(&[1, 3]);
+        let (mut tv, decoder) = Tv::boot_with(&secrets, MAX_FRAME_SIZE as u16);
+        assert_eq!(tv.command(Opcode::Subscribe, &secrets.subscription(1, 100, 5000)), (Opcode::Subscribe, Vec::new()));
+        let frames: Vec<Vec<u8>> = (0..MAX_BATCH).map(|i| vec![i as u8; MAX_FRAME_SIZE]).collect();
+        let body: Vec<u8> = frames.iter().zip(200..).flat_map(|(frame, timestamp)| secrets.encode(1, frame, timestamp)).collect();
+
+        // This is the largest response there is, which the firmware's heap is sized for
+        let (opcode, results) = tv.command(Opcode::BatchDecode, &body);
+        assert_eq!((opcode, results.len()), (Opcode::BatchDecode, MAX_BATCH_RESPONSE));
+        let expected: Vec<(u16, &[u8])> = frames.iter().map(|frame| (STATUS_OK, frame.as_slice())).collect();
+        assert_eq!((opcode, results), batch_results(&expected));
+
+        // The whole batch was persisted before its answer went out
+        drop(tv);
+        let mut board = decoder.join().unwrap();
+        assert_eq!(ReplayLog::load(&mut board.flash).last_frame(1), Some(200 + MAX_BATCH as u64 - 1));
+    }
+
+    #[test]
+    fn batch_refuses_a_truncated_last_packet() {
+        let secrets = Secrets::new(&[1, 3]);
+        let (mut tv, decoder) = subscribed(&secrets);
+        let mut body = [secrets.encode(1, &FRAME, 200), secrets.encode(1, &FRAME, 201)].concat();
+        body.truncate(body.len() - 1);
+        assert_eq!(tv.command(Opcode::BatchDecode, &body), error(DecoderError::BadLength));
+        assert_eq!(tv.command(Opcode::BatchDecode, &secrets.encode(1, &FRAME, 202)), batch_results(&[(STATUS_OK, &FRAME)]));
+
+        drop(tv);
+        decoder.join().unwrap();
+    }
+
+    #[test]
+    fn batch_reads_the_rest_of_the_body_after_a_bad_packet() {
+        let secrets = Secrets::new(&[1, 3]);
+        let (mut tv, decoder) = subscribed(&secrets);
+        // The first packet names a frame longer than the decoder takes, so nothing after it can be split up
+        let mut body = secrets.encode(1, &[7; 65], 200);
+        for timestamp in 201..205 {
+            body.extend(secrets.encode(1, &FRAME, timestamp));
+        }
+        assert!(body.len() > 2 * BLOCK_SIZE);
+        assert_eq!(tv.command(Opcode::BatchDecode, &body), error(DecoderError::BadLength));
+        assert_eq!(tv.unsent, 0);
+        assert_eq!(tv.command(Opcode::List, b""), listing(&[(1, 100, 5000)]));
+
+        drop(tv);
+        decoder.join().unwrap();
+    }
+
+    #[test]
+    fn a_subscription_for_another_decoder_is_refused_before_the_log_is_touched() {
+        let secrets = Secrets::new(&[1]);
+        let (tv, decoder) = Tv::boot(&secrets);
+        drop(tv);
+        let untouched = decoder.join().unwrap().flash;
+
+        // The subscription is signed like any other, so only its device ID gives it away
+        let (mut tv, decoder) = Tv::boot(&secrets);
+        let elsewhere = secrets.subscription_for(DECODER_ID ^ 1, 1, 100, 5000);
+        assert_eq!(tv.command(Opcode::Subscribe, &elsewhere), error(DecoderError::WrongDevice));
+        assert_eq!(tv.command(Opcode::List, b""), listing(&[]));
+        drop(tv);
+        assert!(decoder.join().unwrap().flash.image() == untouched.image());
+    }
+
+    #[test]
+    fn a_full_subscription_table_is_reported_as_full() {
+        let channels: Vec<u32> = (1..=9).collect();
+        let secrets = Secrets::new(&channels);
+        let (mut tv, decoder) = Tv::boot(&secrets);
+        let subscribe = |tv: &mut Tv, channel| tv.command(Opcode::Subscribe, &secrets.subscription(channel, 100, 5000));
+
+        // Slot 0 belongs to the emergency channel, which leaves eight for the others
+        for &channel in &channels[..8] {
+            assert_eq!(subscribe(&mut tv, channel), (Opcode::Subscribe, Vec::new()));
+        }
+        assert_eq!(subscribe(&mut tv, 9), error(DecoderError::TableFull));
+
+        // A channel that already has a slot can still be renewed, and freeing a slot makes room
+        assert_eq!(subscribe(&mut tv, 1), (Opcode::Subscribe, Vec::new()));
+        assert_eq!(tv.command(Opcode::Unsubscribe, &3u32.to_be_bytes()), (Opcode::Unsubscribe, Vec::new()));
+        assert_eq!(subscribe(&mut tv, 9), (Opcode::Subscribe, Vec::new()));
+
+        drop(tv);
+        decoder.join().unwrap();
+    }
+
+    #[test]
+    fn hello_negotiates_the_protocol_version() {
+        let secrets = Secrets::new(&[1]);
+        let (mut tv, decoder) = Tv::boot(&secrets);
+
+        // DecoderHello.FORMAT is "<HHI"
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&MIN_PROTOCOL_VERSION.to_le_bytes());
+        expected.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        expected.extend_from_slice(&(FEATURE_BATCH | FEATURE_CRC | FEATURE_STREAM).to_le_bytes());
+        for version in [MIN_PROTOCOL_VERSION, PROTOCOL_VERSION] {
+            assert_eq!(tv.command(Opcode::Hello, &version.to_le_bytes()), (Opcode::Hello, expected.clone()));
+        }
+        let hello = HelloRecord::decode(&expected).unwrap();
+        assert_eq!(HelloRecord::decode(&hello.encode()), Some(hello));
+
+        // A host older or newer than what the decoder speaks is turned away
+        for version in [0, MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            assert!(!hello.supports(version));
+            assert_eq!(tv.command(Opcode::Hello, &version.to_le_bytes()), error(DecoderError::UnsupportedVersion));
+        }
+        assert_eq!(tv.command(Opcode::Hello, &[PROTOCOL_VERSION as u8]), error(DecoderError::BadLength));
+        assert_eq!(tv.command(Opcode::Hello, &[0; HELLO_REQUEST_SIZE + 1]), error(DecoderError::BadLength));
+
+        drop(tv);
+        decoder.join().unwrap();
+    }
+
+    #[test]
+    fn ping_echoes_its_payload_after_the_link_counters() {
+        let secrets = Secrets::new(&[1]);
+        let (mut tv, decoder) = Tv::boot(&secrets);
+        // LinkStats.FORMAT is "<III", and every byte of the host's messages counts, headers included
+        let ping = |received: u32, framing_errors: u32, payload: &[u8]| {
+            let mut body = Vec::new();
+            for counter in [received, framing_errors, 0] {
+                body.extend_from_slice(&counter.to_le_bytes());
+            }
+            body.extend_from_slice(payload);
+            (Opcode::Ping, body)
+        };
+
+        assert_eq!(tv.command(Opcode::Ping, b"hello"), ping(9, 0, b"hello"));
+        let longest = [0xA5; MAX_PING];
+        assert_eq!(tv.command(Opcode::Ping, &longest), ping(9 + 4 + 256, 0, &longest));
+
+        // A longer payload is refused before any of it is sent, and counted as a framing error
+        assert_eq!(tv.command(Opcode::Ping, &[0; MAX_PING + 1]), error(DecoderError::BadLength));
+        let (opcode, body) = tv.command(Opcode::Ping, b"");
+        assert_eq!((opcode, body.len()), (Opcode::Ping, STATS_SIZE));
+        assert_eq!(LinkStats::decode(&body), Some(LinkStats { received: 269 + 4 + 4, framing_errors: 1, resyncs: 0 }));
+
+        drop(tv);
+        decoder.join().unwrap();
+    }
+
+    #[test]
+    fn info_is_laid_out_as_the_python_host_unpacks_it() {
+        let secrets = Secrets::new(&[1, 3]);
+        let (mut tv, decoder) = Tv::boot(&secrets);
+        assert_eq!(tv.command(Opcode::Subscribe, &secrets.subscription(3, 100, 5000)), (Opcode::Subscribe, Vec::new()));
+
+        // DecoderInfo.FORMAT is "<HI3s20sBBHB16IH"
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        expected.extend_from_slice(&DECODER_ID.to_le_bytes());
+        expected.extend_from_slice(&[1, 0, 0]);
+        expected.extend_from_slice(&[0; 20]);
+        expected.extend_from_slice(&[8, 7]);
+        expected.extend_from_slice(&64u16.to_le_bytes());
+        expected.push(2);
+        for channel in [1u32, 3].into_iter().chain([0; 14]) {
+            expected.extend_from_slice(&channel.to_le_bytes());
+        }
+        expected.extend_from_slice(&0u16.to_le_bytes());
+        assert_eq!(expected.len(), INFO_SIZE);
+
+        let (opcode, body) = tv.command(Opcode::Info, b"");
+        assert_eq!((opcode, &body), (Opcode::Info, &expected));
+        let info = InfoRecord::decode(&body).unwrap();
+        assert_eq!((info.decoder_id, info.free_slots, info.channel_count), (DECODER_ID, 7, 2));
+        assert_eq!(InfoRecord::decode(&info.encode()), Some(info));
+
+        drop(tv);
+        decoder.join().unwrap();
+    }
+}
@@ -0,0 +1,765 @@
+use crate::{check_integrity, test, Board, MAX_PACKET_SIZE, PACKET_OVERHEAD};
+use crate::command::{Commands, Context};
+use crate::error::DecoderError;
+use crate::hw::{ByteIo, Delay, Flash, Rng};
+use crate::protocol::{Block, BlockReassembler, HeaderScanner, InfoRecord, LinkMode, LinkStats, MessageHeader,
+    Opcode, ACK, BLOCK_SIZE, CRC_SIZE, HEADER_SIZE, MAGIC, MAX_BLOCK_RETRIES, NAK, PROTOCOL_VERSION};
+use crate::replay::ReplayGuard;
+use crate::subscription::Subscription;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use ed25519_dalek::{Digest, DigestVerifier, Sha512, Signature, VerifyingKey};
+
+/// Sends a properly formatted debug message to the console.
+/// @param console: The console the message is sent through.
+/// @param bytes: The list of bytes sent through.
+pub fn write_console<C: ByteIo>(console: &mut C, bytes: &[u8]) {
+    write_unacked(console, bytes, Opcode::Debug);
+}
+
+/// Sends a message to the console without waiting for any ACKs, as debug messages and streaming mode do.
+/// @param console: The console the message is sent through.
+/// @param bytes: The list of bytes sent through.
+/// @param opcode: The opcode of the operation.
+pub fn write_unacked<C: ByteIo>(console: &mut C, bytes: &[u8], opcode: Opcode) {
+    console.write_bytes(&MessageHeader::new(opcode, bytes.len() as u16).encode());
+    console.write_bytes(bytes);
+}
+
+/// Sends a properly formatted message to the console.
+/// Gives up on the rest of the message if the TV stops acknowledging it.
+/// @param console: The console the message is sent through.
+/// @param delay: The delay that times the wait for each ACK.
+/// @param bytes: The list of bytes sent through.
+/// @param opcode: The opcode of the operation.
+pub fn write_comm<C: ByteIo, D: Delay>(console: &mut C, delay: &mut D, bytes: &[u8], opcode: Opcode) {
+    console.write_bytes(&MessageHeader::new(opcode, bytes.len() as u16).encode());
+
+    for block in bytes.chunks(BLOCK_SIZE) {
+        if eat_ack(console, delay).is_err() {
+            return;
+        }
+
+        console.write_bytes(block);
+    }
+    let _ = eat_ack(console, delay);
+}
+
+/// Reports an error to the console, as its code followed by its description
+/// @param console: The console the message is sent through.
+/// @param delay: The delay that times the wait for each ACK.
+/// @param err: The error being reported.
+pub fn write_err<C: ByteIo, D: Delay>(console: &mut C, delay: &mut D, err: DecoderError) {
+    write_comm(console, delay, &error_body(err), Opcode::Error);
+}
+
+/// Builds the body of an error message.
+/// @param err: The error being reported.
+/// @return The code of the error followed by its description.
+pub(crate) fn error_body(err: DecoderError) -> Vec<u8> {
+    let mut body = vec![0u8; 2];
+    body.copy_from_slice(&err.code().to_le_bytes());
+    body.extend_from_slice(err.message().as_bytes());
+    body
+}
+
+/// How long the TV may keep the decoder waiting in the middle of a message, in milliseconds
+pub const RX_TIMEOUT_MS: u32 = 1000;
+/// How often a waiting read checks the console, in microseconds, which is well within the time the UART FIFO takes to fill
+const POLL_US: u32 = 100;
+
+/// Reads a byte, giving up if none arrives in time.
+/// @param console: The console the byte arrives through.
+/// @param delay: The delay that times the wait.
+/// @return The byte, or a timeout error.
+pub fn read_timeout<C: ByteIo, D: Delay>(console: &mut C, delay: &mut D) -> Result<u8, DecoderError> {
+    for _ in 0..RX_TIMEOUT_MS * 1000 / POLL_US {
+        if let Some(byte) = console.try_read_byte() {
+            return Ok(byte);
+        }
+        delay.delay_us(POLL_US);
+    }
+    Err(DecoderError::Timeout)
+}
+
+/// Awaits an ACK message from the UART and reads the following bytes.
+/// @param console: The console the ACK arrives through.
+/// @param delay: The delay that times the wait.
+/// @return Nothing, or a timeout error if the ACK never came.
+pub fn eat_ack<C: ByteIo, D: Delay>(console: &mut C, delay: &mut D) -> Result<(), DecoderError> {
+    while read_timeout(console, delay)? != MAGIC {
+        core::hint::spin_loop()
+    }
+    read_timeout(console, delay)?;
+    read_timeout(console, delay)?;
+    read_timeout(console, delay)?;
+    Ok(())
+}
+
+/// Sends an ACK signal to the console.
+/// @param console: The console the ACK is sent through.
+pub fn ack<C: ByteIo>(console: &mut C) {
+    console.write_bytes(&ACK);
+}
+
+/// Waits for the next message header, skipping over anything that can't start one.
+/// Nothing is owed while the link is idle, but once a header starts the rest of it has to follow in time.
+/// @param console: The console the header arrives through.
+/// @param delay: The delay that times the wait.
+/// @param session: The state of the link, whose counters are updated.
+/// @return The header.
+pub fn read_header<C: ByteIo, D: Delay>(console: &mut C, delay: &mut D, session: &mut Session) -> MessageHeader {
+    let mut scanner = HeaderScanner::new();
+    loop {
+        let byte = if scanner.is_idle() {
+            console.read_byte()
+        } else {
+            match read_timeout(console, delay) {
+                Ok(byte) => byte,
+                Err(_) => {
+                    scanner.reset();
+                    session.stats.framing_errors = session.stats.framing_errors.wrapping_add(1);
+                    continue;
+                }
+            }
+        };
+        if let Some(header) = scanner.push(byte) {
+            let skipped = scanner.skipped();
+            session.stats.received = session.stats.received.wrapping_add((skipped + HEADER_SIZE) as u32);
+            if skipped > 0 {
+                session.stats.resyncs = session.stats.resyncs.wrapping_add(1);
+                write_console(console, format!("Skipped {} bytes looking for a header", skipped).as_bytes());
+            }
+            return header;
+        }
+    }
+}
+
+/// Sends a NAK signal to the console, asking for the last block again.
+/// @param console: The console the NAK is sent through.
+pub fn nak<C: ByteIo>(console: &mut C) {
+    console.write_bytes(&NAK);
+}
+
+/// Reads the rest of the current block of a message body.
+/// When it's checked, a block that doesn't match its CRC-32 is NAKed and received again.
+/// @param console: The console the body arrives through.
+/// @param delay: The delay that times the wait.
+/// @param session: The state of the link, which says whether each block is followed by its CRC-32 and whose counters are updated.
+/// @param body: The reassembler collecting the body.
+/// @return The finished block, nothing once the whole body has arrived, or an error if the TV stopped sending
+/// or the block kept arriving corrupted.
+pub fn read_block<'a, C: ByteIo, D: Delay>(console: &mut C, delay: &mut D, session: &mut Session, body: &'a mut BlockReassembler)
+ -> Result<Option<Block<'a>>, DecoderError> {
+    if body.is_complete() {
+        return Ok(None);
+    }
+    let mut retries = 0;
+    loop {
+        let size = body.pending() + if session.crc { CRC_SIZE } else { 0 };
+        let (last, crc) = match read_block_rest(console, delay, session.crc, body) {
+            Ok(rest) => rest,
+            Err(err) => {
+                session.stats.framing_errors = session.stats.framing_errors.wrapping_add(1);
+                return Err(err);
+            }
+        };
+        session.stats.received = session.stats.received.wrapping_add(size as u32);
+        if crc.is_some_and(|crc| !body.check_crc(last, crc)) {
+            session.stats.framing_errors = session.stats.framing_errors.wrapping_add(1);
+            body.discard_block();
+            if retries == MAX_BLOCK_RETRIES {
+                return Err(DecoderError::BadChecksum);
+            }
+            retries += 1;
+            nak(console);
+            continue;
+        }
+        return Ok(body.push(last));
+    }
+}
+
+/// Reads the current block of a message body up to its last byte, which is left for the caller to push once it is checked.
+/// @param console: The console the body arrives through.
+/// @param delay: The delay that times the wait.
+/// @param checked: Whether the block is followed by its CRC-32.
+/// @param body: The reassembler collecting the body.
+/// @return The last byte of the block and its CRC-32 if it is checked, or a timeout error.
+fn read_block_rest<C: ByteIo, D: Delay>(console: &mut C, delay: &mut D, checked: bool, body: &mut BlockReassembler)
+ -> Result<(u8, Option<u32>), DecoderError> {
+    while body.pending() > 1 {
+        body.push(read_timeout(console, delay)?);
+    }
+    let last = read_timeout(console, delay)?;
+    if !checked {
+        return Ok((last, None));
+    }
+    let mut crc = [0u8; CRC_SIZE];
+    for byte in &mut crc {
+        *byte = read_timeout(console, delay)?;
+    }
+    Ok((last, Some(u32::from_le_bytes(crc))))
+}
+
+/// Receives the whole body of a message, acknowledging each block.
+/// @param console: The console the body arrives through.
+/// @param delay: The delay that times the wait.
+/// @param session: The state of the link, which says whether each block is followed by its CRC-32 and whose counters are updated.
+/// @param header: The header of the message.
+/// @return The body of the message, or an error if the TV stopped sending or a block kept arriving corrupted.
+pub fn read_body<C: ByteIo, D: Delay>(console: &mut C, delay: &mut D, session: &mut Session, header: &MessageHeader)
+ -> Result<Vec<u8>, DecoderError> {
+    let mut byte_list = vec![0u8; header.length as usize];
+    read_body_into(console, delay, session, header, &mut byte_list)?;
+    Ok(byte_list)
+}
+
+/// Receives the whole body of a message into a buffer, acknowledging each block.
+/// @param console: The console the body arrives through.
+/// @param delay: The delay that times the wait.
+/// @param session: The state of the link, which says whether each block is followed by its CRC-32 and whose counters are updated.
+/// @param header: The header of the message.
+/// @param buffer: Where the body goes, which must be at least as long as the body.
+/// @return Nothing, or an error if the body doesn't fit, the TV stopped sending or a block kept arriving corrupted.
+pub fn read_body_into<C: ByteIo, D: Delay>(console: &mut C, delay: &mut D, session: &mut Session, header: &MessageHeader,
+    buffer: &mut [u8]) -> Result<(), DecoderError> {
+    // Refused before the first ACK, so the TV doesn't send a body with nowhere to go
+    if header.length as usize > buffer.len() {
+        session.stats.framing_errors = session.stats.framing_errors.wrapping_add(1);
+        return Err(DecoderError::BadLength);
+    }
+    let mut body = BlockReassembler::new(header);
+    ack(console);
+    while let Some(block) = read_block(console, delay, session, &mut body)? {
+        buffer[block.offset..block.offset + block.data.len()].copy_from_slice(block.data);
+        ack(console);
+    }
+    Ok(())
+}
+
+/// Answers a message received in streaming mode, through the handler registered for its opcode
+/// @param ctx The decoder state the command works on
+/// @param header The header of the message
+/// @param commands The handlers for the commands the decoder answers
+fn stream_resp<C: ByteIo, F: Flash, R: Rng, D: Delay>(ctx: &mut Context<C, F, R, D>, header: &MessageHeader, commands: &Commands<C, F, R, D>) {
+    let mut byte_list = [0u8; MAX_PACKET_SIZE];
+    let length = read_unacked(&mut ctx.board.console, &mut ctx.board.delay, ctx.session, header, &mut byte_list);
+    // A message that stopped short still owes the host an answer, or its credit would be lost
+    if let Err(DecoderError::Timeout) = length {
+        write_unacked(&mut ctx.board.console, &error_body(DecoderError::Timeout), Opcode::Error);
+        return;
+    }
+    let Some(handler) = commands.find(header.opcode) else {
+        write_unacked(&mut ctx.board.console, &error_body(DecoderError::UnknownOpcode), Opcode::Error);
+        return;
+    };
+    handler.handle_streaming(ctx, length.map(|length| &byte_list[..length]));
+}
+
+/// Receives the whole body of a message without acknowledging anything, as streaming mode does.
+/// @param console: The console the body arrives through.
+/// @param delay: The delay that times the wait.
+/// @param session: The state of the link, whose counters are updated.
+/// @param header: The header of the message.
+/// @param buffer: Where the body goes.
+/// @return The length of the body, or an error if it didn't fit and was thrown away or the TV stopped sending.
+pub fn read_unacked<C: ByteIo, D: Delay>(console: &mut C, delay: &mut D, session: &mut Session, header: &MessageHeader,
+    buffer: &mut [u8]) -> Result<usize, DecoderError> {
+    // The whole body is always read, so the next header is where it should be
+    for i in 0..header.length as usize {
+        let Ok(byte) = read_timeout(console, delay) else {
+            session.stats.framing_errors = session.stats.framing_errors.wrapping_add(1);
+            return Err(DecoderError::Timeout);
+        };
+        if let Some(slot) = buffer.get_mut(i) {
+            *slot = byte;
+        }
+    }
+    session.stats.received = session.stats.received.wrapping_add(header.length as u32);
+    if header.length as usize > buffer.len() {
+        session.stats.framing_errors = session.stats.framing_errors.wrapping_add(1);
+        return Err(DecoderError::BadLength);
+    }
+    Ok(header.length as usize)
+}
+
+/// The state of the link to the TV, which lasts across commands
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Session {
+    /// How messages are exchanged
+    pub mode: LinkMode,
+    /// The number of DECODE messages the host may have waiting in streaming mode
+    pub window: u16,
+    /// Whether each block the host sends in lock-step is followed by its CRC-32
+    pub crc: bool,
+    /// What has been seen on the link, which PING reports
+    pub stats: LinkStats,
+}
+
+/// Reads whatever the TV is sending over right now, and responds to it.
+/// @param ctx: The decoder state the commands work on, with the console the TV talks through.
+/// @param commands: The handlers for the commands the decoder answers.
+pub fn read_resp<C: ByteIo, F: Flash, R: Rng, D: Delay>(ctx: &mut Context<C, F, R, D>, commands: &Commands<C, F, R, D>) {
+    // Waits for a header starting with the magic byte % and an opcode we take, skipping anything else
+    let header = read_header(&mut ctx.board.console, &mut ctx.board.delay, ctx.session);
+
+    if ctx.session.mode == LinkMode::Streaming {
+        stream_resp(ctx, &header, commands);
+        return;
+    }
+
+    // Every command the decoder answers has a handler, found by its opcode
+    let Some(handler) = commands.find(header.opcode) else {
+        write_err(&mut ctx.board.console, &mut ctx.board.delay, DecoderError::UnknownOpcode);
+        return;
+    };
+
+    // A body longer than the command takes is turned away in place of the first ACK, before any of it is sent
+    if header.length as usize > handler.max_payload() {
+        ctx.session.stats.framing_errors = ctx.session.stats.framing_errors.wrapping_add(1);
+        write_err(&mut ctx.board.console, &mut ctx.board.delay, DecoderError::BadLength);
+        return;
+    }
+
+    // Delays to avoid side channel attacks, and reports a failure before the host sends anything more
+    if handler.needs_integrity() && !test(ctx.board) {
+        return;
+    }
+
+    handler.handle(ctx, &header);
+}
+
+/// Describes the decoder for the INFO command
+/// @param board The board, with the build information and channel list
+/// @param subscriptions The subscription list
+/// @param corrupt The channels whose stored subscription failed its MAC
+/// @return The record sent to the host
+pub fn device_info<C, F, R, D>(board: &Board<C, F, R, D>, subscriptions: &[Option<Subscription>; 9], corrupt: &[u32]) -> InfoRecord {
+    // The first entry of both lists is the emergency channel, which is always there
+    let mut channels = [0u32; 16];
+    channels.copy_from_slice(&board.keys.channels[1..]);
+    let corrupt = channels.iter().enumerate()
+        .filter(|&(_, channel)| *channel != 0 && corrupt.contains(channel))
+        .fold(0u16, |bits, (i, _)| bits | 1 << i);
+    InfoRecord {
+        protocol_version: PROTOCOL_VERSION,
+        decoder_id: board.info.decoder_id,
+        firmware_version: board.info.version,
+        git_hash: board.info.git_hash,
+        slots: (subscriptions.len() - 1) as u8,
+        free_slots: subscriptions[1..].iter().filter(|sub| sub.is_none()).count() as u8,
+        max_frame_size: board.info.max_frame_size,
+        channel_count: channels.iter().filter(|&&channel| channel != 0).count() as u8,
+        channels,
+        corrupt,
+    }
+}
+
+/// Performs the decoding sequence, persisting the frame's timestamp before handing the frame back
+/// @param board The board, with the flash holding the subscriptions
+/// @param subscriptions The subscription list
+/// @param replay The decoder-wide replay protection state
+/// @param verifier The verifying key for the decoded frame
+/// @param byte_list The list of bytes received from the encoder
+/// @return Either the successfully decoded frame, without its padding, or the reason it was rejected
+pub fn decode_subroutine<C: ByteIo, F: Flash, R: Rng, D: Delay>(board: &mut Board<C, F, R, D>, subscriptions: &mut [Option<Subscription>; 9],
+    replay: &mut ReplayGuard, verifier: VerifyingKey, byte_list: &[u8])
+ -> Result<Vec<u8>, DecoderError> {
+    let frame = decode_frame(board, subscriptions, replay, verifier, byte_list)?;
+    replay.flush(&mut board.flash)?;
+    Ok(frame)
+}
+
+/// Performs the decoding sequence, leaving the frame's timestamp for the caller to persist with a replay flush
+/// before the frame goes out
+/// @param board The board, with the flash holding the subscriptions
+/// @param subscriptions The subscription list
+/// @param replay The decoder-wide replay protection state
+/// @param verifier The verifying key for the decoded frame
+/// @param byte_list The list of bytes received from the encoder
+/// @return Either the successfully decoded frame, without its padding, or the reason it was rejected
+pub fn decode_frame<C: ByteIo, F: Flash, R: Rng, D: Delay>(board: &mut Board<C, F, R, D>, subscriptions: &mut [Option<Subscription>; 9],
+    replay: &mut ReplayGuard, verifier: VerifyingKey, byte_list: &[u8])
+ -> Result<Vec<u8>, DecoderError> {
+    check_integrity(board)?;
+
+    // The packet has to hold exactly the frame it names, and no more than the decoder takes
+    if byte_list.len() < PACKET_OVERHEAD {
+        return Err(DecoderError::BadLength);
+    }
+    let length: u16 = u16::from_be_bytes(byte_list[12..14].try_into().unwrap());
+    if length as usize != byte_list.len() - PACKET_OVERHEAD || length > board.info.max_frame_size {
+        return Err(DecoderError::BadLength);
+    }
+
+    // Splits up the data
+    let channel: u32 = u32::from_be_bytes(byte_list[0..4].try_into().unwrap());
+    let timestamp: u64 = u64::from_be_bytes(byte_list[4..12].try_into().unwrap());
+    let signature: Signature = Signature::from_slice(&byte_list[14..78]).unwrap(); // 64 bytes
+    let mut frame = byte_list[PACKET_OVERHEAD..].to_vec();
+
+    // Get the relevant subscription from the live table, so that its replay state outlives this frame
+    let slot = subscriptions.iter().position(|sub_i| sub_i.is_some_and(|sub_i| sub_i.channel == channel))
+        .ok_or(DecoderError::NotSubscribed)?;
+    let sub: Subscription = subscriptions[slot].unwrap();
+
+    // Tests that the subscription is valid
+    if sub.start > timestamp {
+        return Err(DecoderError::BeforeStart);
+    } else if sub.end <= timestamp {
+        return Err(DecoderError::SubscriptionExpired);
+    }
+
+    // An old timestamp means the frame was recorded and played back, which security requirement #3 rules out
+    if !replay.is_fresh(&sub, timestamp) {
+        return Err(DecoderError::Replayed);
+    }
+
+    check_integrity(board)?;
+
+    // Decodes the encrypted frame
+    let random = board.trng.gen_u32();
+    let ans = random.wrapping_mul(random);
+
+    sub.decode(&board.flash, &board.keys, &mut frame, timestamp);
+
+    if random.wrapping_mul(random) != ans {
+        return Err(DecoderError::IntegrityCheck);
+    }
+
+    // Verifies that the frame satisfies the signature by running ED25519 on the hashed length and frame,
+    // which is the one signature covering however many keystream blocks the frame took
+    let ret_digest = Sha512::default().chain_update(length.to_be_bytes()).chain_update(&frame);
+
+    let chan_bytes = channel.to_be_bytes();
+    check_integrity(board)?;
+    let verifier_context = verifier.with_context(&chan_bytes).unwrap();
+    verifier_context.verify_digest(ret_digest, &signature).map_err(|_| DecoderError::SignatureFailed)?;
+
+    // Only a verified frame moves the channel's replay state forward
+    if let Some(live) = subscriptions[slot].as_mut() {
+        replay.accept(live, timestamp)?;
+    }
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{board, error, install, listing, Secrets, TestBoard, Tv};
+    use crate::host::PipeIo;
+    use crate::replay::ReplayPolicy;
+    use crate::{load_subscriptions, MAX_FRAME_SIZE};
+    use crate::protocol::{crc32, encode_stream, STATS_SIZE};
+
+    const FRAME: [u8; 64] = [7; 64];
+
+    /// A delay that takes no time, and has the TV send the rest of what it has to say only once the decoder has given up waiting
+    struct Stall {
+        tv: PipeIo,
+        waited: u32,
+        rest: Vec<u8>,
+    }
+
+    impl Stall {
+        fn new(tv: PipeIo, rest: &[u8]) -> Stall {
+            Stall { tv, waited: 0, rest: rest.to_vec() }
+        }
+
+        /// Reads the next message the decoder sent the TV
+        fn receive(&mut self) -> (MessageHeader, Vec<u8>) {
+            let mut header = [0u8; HEADER_SIZE];
+            header.iter_mut().for_each(|byte| *byte = self.tv.read_byte());
+            let header = MessageHeader::decode(&header).unwrap();
+            let body = (0..header.length).map(|_| self.tv.read_byte()).collect();
+            (header, body)
+        }
+    }
+
+    impl Delay for Stall {
+        fn delay_us(&mut self, us: u32) {
+            self.waited += us;
+            if self.waited >= RX_TIMEOUT_MS * 1000 {
+                self.waited = 0;
+                let rest = core::mem::take(&mut self.rest);
+                self.tv.write_bytes(&rest);
+            }
+        }
+    }
+
+    #[test]
+    fn junk_before_a_header_is_skipped_and_counted() {
+        let (tv, mut console) = PipeIo::pair();
+        let mut delay = Stall::new(tv, b"");
+        let mut session = Session::default();
+        let list = MessageHeader::new(Opcode::List, 0);
+
+        // A magic byte followed by another one only loses the first
+        delay.tv.write_bytes(b"junk%");
+        delay.tv.write_bytes(&list.encode());
+        assert_eq!(read_header(&mut console, &mut delay, &mut session), list);
+        assert_eq!(session.stats, LinkStats { received: 9, framing_errors: 0, resyncs: 1 });
+        let (header, body) = delay.receive();
+        assert_eq!(header.opcode, Opcode::Debug);
+        assert_eq!(body, b"Skipped 5 bytes looking for a header");
+
+        // A header straight after another isn't a resync
+        delay.tv.write_bytes(&list.encode());
+        assert_eq!(read_header(&mut console, &mut delay, &mut session), list);
+        assert_eq!(session.stats, LinkStats { received: 13, framing_errors: 0, resyncs: 1 });
+    }
+
+    #[test]
+    fn a_header_that_stops_short_is_dropped_after_the_timeout() {
+        let (tv, mut console) = PipeIo::pair();
+        let list = MessageHeader::new(Opcode::List, 0);
+        let mut delay = Stall::new(tv, &list.encode());
+        let mut session = Session::default();
+
+        // The three bytes that arrived before the stall can't be the start of the header that follows it
+        delay.tv.write_bytes(&MessageHeader::new(Opcode::Subscribe, 0).encode()[..3]);
+        assert_eq!(read_header(&mut console, &mut delay, &mut session), list);
+        assert_eq!(session.stats, LinkStats { received: 7, framing_errors: 1, resyncs: 1 });
+    }
+
+    #[test]
+    fn a_body_that_stops_short_times_out() {
+        let (tv, mut console) = PipeIo::pair();
+        let mut delay = Stall::new(tv, b"");
+        let mut session = Session::default();
+
+        // The first block is cut off part of the way through
+        let header = MessageHeader::new(Opcode::Subscribe, (BLOCK_SIZE + 10) as u16);
+        delay.tv.write_bytes(&[1; 100]);
+        assert_eq!(read_body(&mut console, &mut delay, &mut session, &header), Err(DecoderError::Timeout));
+        assert_eq!(delay.receive(), (MessageHeader::new(Opcode::Ack, 0), Vec::new()));
+        assert_eq!(session.stats, LinkStats { received: 0, framing_errors: 1, resyncs: 0 });
+
+        // So is the last block, once the first one has been ACKed
+        delay.tv.write_bytes(&[1; BLOCK_SIZE + 9]);
+        assert_eq!(read_body(&mut console, &mut delay, &mut session, &header), Err(DecoderError::Timeout));
+        assert_eq!(delay.receive(), (MessageHeader::new(Opcode::Ack, 0), Vec::new()));
+        assert_eq!(delay.receive(), (MessageHeader::new(Opcode::Ack, 0), Vec::new()));
+        assert_eq!(session.stats, LinkStats { received: BLOCK_SIZE as u32, framing_errors: 2, resyncs: 0 });
+    }
+
+    /// Appends a block's CRC-32, with one bit of the block flipped if it is to arrive corrupted
+    fn checked(block: &[u8], corrupt: bool) -> Vec<u8> {
+        let mut sent = block.to_vec();
+        sent.extend_from_slice(&crc32(block).to_le_bytes());
+        if corrupt {
+            sent[block.len() / 2] ^= 0x10;
+        }
+        sent
+    }
+
+    #[test]
+    fn a_corrupted_block_is_naked_and_taken_again() {
+        let (tv, mut console) = PipeIo::pair();
+        let mut delay = Stall::new(tv, b"");
+        let mut session = Session { crc: true, ..Session::default() };
+        let body: Vec<u8> = (0..BLOCK_SIZE + 10).map(|i| i as u8).collect();
+        let header = MessageHeader::new(Opcode::Subscribe, body.len() as u16);
+
+        // The first block arrives with a bit flipped, and then intact
+        delay.tv.write_bytes(&checked(&body[..BLOCK_SIZE], true));
+        delay.tv.write_bytes(&checked(&body[..BLOCK_SIZE], false));
+        delay.tv.write_bytes(&checked(&body[BLOCK_SIZE..], false));
+        assert_eq!(read_body(&mut console, &mut delay, &mut session, &header), Ok(body));
+        for signal in [ACK, NAK, ACK, ACK] {
+            assert_eq!(delay.receive().0.encode(), signal);
+        }
+        let received = 2 * (BLOCK_SIZE + CRC_SIZE) + 10 + CRC_SIZE;
+        assert_eq!(session.stats, LinkStats { received: received as u32, framing_errors: 1, resyncs: 0 });
+    }
+
+    #[test]
+    fn a_block_that_keeps_arriving_corrupted_is_given_up_on() {
+        let (tv, mut console) = PipeIo::pair();
+        let mut delay = Stall::new(tv, b"");
+        let mut session = Session { crc: true, ..Session::default() };
+        let header = MessageHeader::new(Opcode::Subscribe, 20);
+
+        // The first try and every retry fail, and nothing more is asked for after the last
+        for _ in 0..=MAX_BLOCK_RETRIES {
+            delay.tv.write_bytes(&checked(&[5; 20], true));
+        }
+        assert_eq!(read_body(&mut console, &mut delay, &mut session, &header), Err(DecoderError::BadChecksum));
+        assert_eq!(delay.receive().0.encode(), ACK);
+        for _ in 0..MAX_BLOCK_RETRIES {
+            assert_eq!(delay.receive().0.encode(), NAK);
+        }
+        assert_eq!(delay.tv.try_read_byte(), None);
+        let tries = (MAX_BLOCK_RETRIES + 1) as u32;
+        assert_eq!(session.stats, LinkStats { received: tries * (20 + CRC_SIZE as u32), framing_errors: tries, resyncs: 0 });
+    }
+
+    #[test]
+    fn a_stalled_host_gets_a_timeout_and_the_link_recovers() {
+        let secrets = Secrets::new(&[1]);
+        let (mut tv, decoder) = Tv::boot(&secrets);
+        let subscription = secrets.subscription(1, 100, 5000);
+
+        // The header is ACKed, and then nothing of the body ever comes
+        tv.link.write_bytes(&MessageHeader::new(Opcode::Subscribe, subscription.len() as u16).encode());
+        assert_eq!(tv.receive(true), (Opcode::Ack, Vec::new()));
+        assert_eq!(tv.receive(true), error(DecoderError::Timeout));
+
+        assert_eq!(tv.command(Opcode::Subscribe, &subscription), (Opcode::Subscribe, Vec::new()));
+        let (opcode, body) = tv.command(Opcode::Ping, b"");
+        assert_eq!((opcode, body.len()), (Opcode::Ping, STATS_SIZE));
+        let stats = LinkStats::decode(&body).unwrap();
+        assert_eq!((stats.framing_errors, stats.resyncs), (1, 0));
+
+        drop(tv);
+        decoder.join().unwrap();
+    }
+
+    #[test]
+    fn command_loop_answers_the_host_tools() {
+        let secrets = Secrets::new(&[1, 3]);
+        let (mut tv, decoder) = Tv::boot(&secrets);
+        let decode = |channel, timestamp| secrets.encode(channel, &FRAME, timestamp);
+
+        assert_eq!(tv.command(Opcode::List, b""), listing(&[]));
+        assert_eq!(tv.command(Opcode::Subscribe, &secrets.subscription(1, 100, 5000)), (Opcode::Subscribe, Vec::new()));
+        assert_eq!(tv.command(Opcode::List, b""), listing(&[(1, 100, 5000)]));
+        let mut forged = secrets.subscription(3, 100, 5000);
+        forged[8] ^= 1;
+        assert_eq!(tv.command(Opcode::Subscribe, &forged), error(DecoderError::SubscriptionSignature));
+
+        assert_eq!(tv.command(Opcode::Decode, &decode(1, 200)), (Opcode::Decode, FRAME.to_vec()));
+        assert_eq!(tv.command(Opcode::Decode, &decode(1, 200)), error(DecoderError::Replayed));
+        assert_eq!(tv.command(Opcode::Decode, &decode(0, 50)), (Opcode::Decode, FRAME.to_vec()));
+        assert_eq!(tv.command(Opcode::Decode, &decode(3, 200)), error(DecoderError::NotSubscribed));
+
+        assert_eq!(tv.command(Opcode::Unsubscribe, &1u32.to_be_bytes()), (Opcode::Unsubscribe, Vec::new()));
+        assert_eq!(tv.command(Opcode::List, b""), listing(&[]));
+        assert_eq!(tv.command(Opcode::Decode, &decode(1, 300)), error(DecoderError::NotSubscribed));
+
+        drop(tv);
+        decoder.join().unwrap();
+    }
+
+    #[test]
+    fn oversized_bodies_are_refused_before_the_first_ack() {
+        let secrets = Secrets::new(&[1]);
+        let (mut tv, decoder) = Tv::boot(&secrets);
+        let subscription = secrets.subscription(1, 100, 5000);
+
+        // Nothing of the body is asked for, so the TV is never left sending into a buffer that can't take it
+        let mut oversized = subscription.clone();
+        oversized.push(0);
+        assert_eq!(tv.command(Opcode::Subscribe, &oversized), error(DecoderError::BadLength));
+        assert_eq!(tv.command(Opcode::Decode, &[0; MAX_PACKET_SIZE + 1]), error(DecoderError::BadLength));
+        assert_eq!(tv.command(Opcode::List, &[0]), error(DecoderError::BadLength));
+
+        // The link is still in step afterwards, with a body spread over several blocks
+        assert!(subscription.len() > BLOCK_SIZE);
+        assert_eq!(tv.command(Opcode::Subscribe, &subscription), (Opcode::Subscribe, Vec::new()));
+        assert_eq!(tv.command(Opcode::List, b""), listing(&[(1, 100, 5000)]));
+
+        drop(tv);
+        decoder.join().unwrap();
+    }
+
+    #[test]
+    fn streaming_messages_go_through_the_command_table() {
+        let secrets = Secrets::new(&[1]);
+        let (mut tv, decoder) = Tv::boot(&secrets);
+        assert_eq!(tv.command(Opcode::Subscribe, &secrets.subscription(1, 100, 5000)), (Opcode::Subscribe, Vec::new()));
+        let streaming = encode_stream(LinkMode::Streaming, 4);
+        assert_eq!(tv.command(Opcode::Stream, &streaming), (Opcode::Stream, streaming.to_vec()));
+
+        // The whole window goes out before the first answer is read
+        tv.send(Opcode::Decode, &secrets.encode(1, &FRAME, 200));
+        tv.send(Opcode::Decode, &secrets.encode(1, &FRAME, 200));
+        tv.send(Opcode::List, b"");
+        tv.send(Opcode::Stream, &encode_stream(LinkMode::LockStep, 0));
+        assert_eq!(tv.receive(false), (Opcode::Decode, FRAME.to_vec()));
+        assert_eq!(tv.receive(false), error(DecoderError::Replayed));
+        assert_eq!(tv.receive(false), error(DecoderError::UnknownOpcode));
+        assert_eq!(tv.receive(false), (Opcode::Stream, encode_stream(LinkMode::LockStep, 0).to_vec()));
+
+        assert_eq!(tv.command(Opcode::List, b""), listing(&[(1, 100, 5000)]));
+        drop(tv);
+        decoder.join().unwrap();
+    }
+
+    /// A decoder subscribed to channel 1, with the subscription list and replay state DECODE works on
+    fn subscribed(secrets: &Secrets) -> (TestBoard, [Option<Subscription>; 9], ReplayGuard) {
+        let mut board = board(secrets);
+        let mut subscriptions = load_subscriptions(&mut board);
+        let replay = ReplayGuard::load(ReplayPolicy::StrictPerChannel, &mut board.flash);
+        install(&mut board, &mut subscriptions, &replay, &secrets.subscription(1, 100, 5000));
+        (board, subscriptions, replay)
+    }
+
+    #[test]
+    fn frames_shorter_than_a_block_come_back_unpadded() {
+        let secrets = Secrets::new(&[1]);
+        let (mut board, mut subscriptions, mut replay) = subscribed(&secrets);
+        let packet = secrets.encode(1, &[0x5A], 200);
+        assert_eq!(packet.len(), PACKET_OVERHEAD + 1);
+        assert_eq!(decode_subroutine(&mut board, &mut subscriptions, &mut replay, secrets.verifier(), &packet), Ok(vec![0x5A]));
+    }
+
+    #[test]
+    fn the_signature_covers_the_length() {
+        let secrets = Secrets::new(&[1]);
+        let (mut board, mut subscriptions, mut replay) = subscribed(&secrets);
+        let packet = secrets.encode(1, &FRAME, 200);
+
+        // A length that disagrees with the packet is turned away before anything is decoded
+        let mut stretched = packet.clone();
+        stretched[13] -= 1;
+        assert_eq!(decode_subroutine(&mut board, &mut subscriptions, &mut replay, secrets.verifier(), &stretched),
+            Err(DecoderError::BadLength));
+
+        // One that agrees because the frame was cut short with it still fails the signature
+        let mut cut = packet[..packet.len() - 1].to_vec();
+        cut[13] -= 1;
+        assert_eq!(decode_subroutine(&mut board, &mut subscriptions, &mut replay, secrets.verifier(), &cut),
+            Err(DecoderError::SignatureFailed));
+
+        // And neither of them counted as seeing the frame
+        assert_eq!(decode_subroutine(&mut board, &mut subscriptions, &mut replay, secrets.verifier(), &packet), Ok(FRAME.to_vec()));
+    }
+
+    #[test]
+    fn frames_up_to_the_largest_size_decode_across_keystream_blocks() {
+        let secrets = Secrets::new(&[1]);
+        let (mut board, mut subscriptions, mut replay) = subscribed(&secrets);
+        board.info.max_frame_size = MAX_FRAME_SIZE as u16;
+
+        // Each keystream block is 64 bytes, so everything past the first takes more of the BLAKE3 output
+        for (timestamp, length) in (200..).zip([64, 65, 300, MAX_FRAME_SIZE]) {
+            let frame: Vec<u8> = (0..length).map(|i| (i * 7) as u8).collect();
+            let packet = secrets.encode(1, &frame, timestamp);
+            assert_eq!(decode_subroutine(&mut board, &mut subscriptions, &mut replay, secrets.verifier(), &packet), Ok(frame));
+        }
+
+        let packet = secrets.encode(1, &[1; MAX_FRAME_SIZE + 1], 300);
+        assert_eq!(decode_subroutine(&mut board, &mut subscriptions, &mut replay, secrets.verifier(), &packet),
+            Err(DecoderError::BadLength));
+    }
+
+    #[test]
+    fn frames_past_the_configured_size_are_refused() {
+        let secrets = Secrets::new(&[1]);
+        let (mut board, mut subscriptions, mut replay) = subscribed(&secrets);
+        assert_eq!(board.info.max_frame_size, 64);
+        let packet = secrets.encode(1, &[1; 65], 200);
+        assert_eq!(decode_subroutine(&mut board, &mut subscriptions, &mut replay, secrets.verifier(), &packet),
+            Err(DecoderError::BadLength));
+    }
+
+    #[test]
+    fn info_flags_corrupt_subscriptions() {
+        let secrets = Secrets::new(&[1, 3, 7]);
+        let info = device_info(&board(&secrets), &[None; 9], &[7, 1234]);
+        assert_eq!((info.channel_count, info.corrupt), (3, 0b100));
+        assert_eq!(InfoRecord::decode(&info.encode()), Some(info));
+    }
+}
@@ -0,0 +1,164 @@
+//! Every failure the decoder reports to the host.
+//! Each error has a stable numeric code, sent little-endian at the start of an `E` response and followed by a
+//! short description, so host tools can act on the code while people can still read the text.
+//! The high byte of a code names the family of the failure and the low byte the failure itself.
+//! Codes are never reused or renumbered; new failures get new codes.
+
+use crate::hw::FlashError;
+
+/// A failure reported to the host
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecoderError {
+    /// The flash refused an operation
+    Flash(FlashError),
+    /// A flash read or write was given a buffer shorter than its length
+    BufferTooSmall,
+    /// The subscription pages have no room left, even after compaction
+    StoreFull,
+    /// The replay log has no room for another channel's timestamp
+    ReplayLogFull,
+    /// The host tried to replace or remove the compiled-in emergency subscription
+    EmergencySubscription,
+    /// The channel isn't one this decoder was built for
+    UnknownChannel,
+    /// A subscription was written but couldn't be read back
+    SubscriptionLoad,
+    /// There is no subscription for the channel of a frame
+    NotSubscribed,
+    /// The subscription was made for a different decoder
+    WrongDevice,
+    /// Every subscription slot is taken by another channel
+    TableFull,
+    /// The frame comes after the end of its subscription
+    SubscriptionExpired,
+    /// The frame comes before the start of its subscription
+    BeforeStart,
+    /// The frame isn't newer than one already decoded
+    Replayed,
+    /// The header named an opcode the decoder doesn't take commands for
+    UnknownOpcode,
+    /// The body of a message is the wrong size for its opcode
+    BadLength,
+    /// The host stopped sending in the middle of a message, or stopped acknowledging one
+    Timeout,
+    /// A block kept failing its CRC-32, even after being sent again
+    BadChecksum,
+    /// The host speaks a protocol revision the decoder doesn't
+    UnsupportedVersion,
+    /// The decoded frame didn't match its signature
+    SignatureFailed,
+    /// The compiled-in verification key is unusable
+    BadVerificationKey,
+    /// The subscription didn't match its signature, so it was forged or spliced together
+    SubscriptionSignature,
+    /// A redundant computation disagreed with itself, which points to fault injection
+    IntegrityCheck,
+    /// The firmware panicked
+    Panic,
+}
+
+impl DecoderError {
+    /// Gets the stable code sent to the host
+    /// @return The error code
+    pub fn code(&self) -> u16 {
+        match self {
+            DecoderError::Flash(FlashError::InvalidAddress) => 0x0101,
+            DecoderError::Flash(FlashError::AccessViolation) => 0x0102,
+            DecoderError::Flash(FlashError::NeedsErase) => 0x0103,
+            DecoderError::BufferTooSmall => 0x0104,
+            DecoderError::StoreFull => 0x0105,
+            DecoderError::ReplayLogFull => 0x0106,
+            DecoderError::EmergencySubscription => 0x0201,
+            DecoderError::UnknownChannel => 0x0202,
+            DecoderError::SubscriptionLoad => 0x0203,
+            DecoderError::NotSubscribed => 0x0204,
+            DecoderError::SubscriptionExpired => 0x0205,
+            DecoderError::BeforeStart => 0x0206,
+            DecoderError::Replayed => 0x0207,
+            DecoderError::WrongDevice => 0x0208,
+            DecoderError::TableFull => 0x0209,
+            DecoderError::UnknownOpcode => 0x0301,
+            DecoderError::BadLength => 0x0302,
+            DecoderError::Timeout => 0x0303,
+            DecoderError::BadChecksum => 0x0304,
+            DecoderError::UnsupportedVersion => 0x0305,
+            DecoderError::SignatureFailed => 0x0401,
+            DecoderError::BadVerificationKey => 0x0402,
+            DecoderError::SubscriptionSignature => 0x0403,
+            DecoderError::IntegrityCheck => 0x0501,
+            DecoderError::Panic => 0x0601,
+        }
+    }
+
+    /// Gets the description sent to the host after the code
+    /// @return A short description of the error
+    pub fn message(&self) -> &'static str {
+        match self {
+            DecoderError::Flash(FlashError::InvalidAddress) => "Flash address is invalid",
+            DecoderError::Flash(FlashError::AccessViolation) => "Flash access violation",
+            DecoderError::Flash(FlashError::NeedsErase) => "Flash page needs an erase",
+            DecoderError::BufferTooSmall => "Buffer is too small",
+            DecoderError::StoreFull => "Subscription store is full",
+            DecoderError::ReplayLogFull => "Replay log is full",
+            DecoderError::EmergencySubscription => "Cannot change the emergency subscription",
+            DecoderError::UnknownChannel => "Channel does not exist",
+            DecoderError::SubscriptionLoad => "Failed to load subscription",
+            DecoderError::NotSubscribed => "No subscription for this channel",
+            DecoderError::SubscriptionExpired => "Timestamp is too late",
+            DecoderError::BeforeStart => "Timestamp is too early",
+            DecoderError::Replayed => "Timestamp is out of order",
+            DecoderError::WrongDevice => "Subscription is for another decoder",
+            DecoderError::TableFull => "Subscription table is full",
+            DecoderError::UnknownOpcode => "Unknown opcode",
+            DecoderError::BadLength => "Message has the wrong length",
+            DecoderError::Timeout => "Timed out waiting for the host",
+            DecoderError::BadChecksum => "Block failed its checksum too many times",
+            DecoderError::UnsupportedVersion => "Protocol revision isn't supported",
+            DecoderError::SignatureFailed => "Frame signature verification failed",
+            DecoderError::BadVerificationKey => "Verification key is invalid",
+            DecoderError::SubscriptionSignature => "Subscription signature verification failed",
+            DecoderError::IntegrityCheck => "Integrity check failed",
+            DecoderError::Panic => "Panic",
+        }
+    }
+
+    /// Gets the error back from its code
+    /// @param code An error code
+    /// @return The error, or nothing if no error has that code
+    pub fn from_code(code: u16) -> Option<DecoderError> {
+        Some(match code {
+            0x0101 => DecoderError::Flash(FlashError::InvalidAddress),
+            0x0102 => DecoderError::Flash(FlashError::AccessViolation),
+            0x0103 => DecoderError::Flash(FlashError::NeedsErase),
+            0x0104 => DecoderError::BufferTooSmall,
+            0x0105 => DecoderError::StoreFull,
+            0x0106 => DecoderError::ReplayLogFull,
+            0x0201 => DecoderError::EmergencySubscription,
+            0x0202 => DecoderError::UnknownChannel,
+            0x0203 => DecoderError::SubscriptionLoad,
+            0x0204 => DecoderError::NotSubscribed,
+            0x0205 => DecoderError::SubscriptionExpired,
+            0x0206 => DecoderError::BeforeStart,
+            0x0207 => DecoderError::Replayed,
+            0x0208 => DecoderError::WrongDevice,
+            0x0209 => DecoderError::TableFull,
+            0x0301 => DecoderError::UnknownOpcode,
+            0x0302 => DecoderError::BadLength,
+            0x0303 => DecoderError::Timeout,
+            0x0304 => DecoderError::BadChecksum,
+            0x0305 => DecoderError::UnsupportedVersion,
+            0x0401 => DecoderError::SignatureFailed,
+            0x0402 => DecoderError::BadVerificationKey,
+            0x0403 => DecoderError::SubscriptionSignature,
+            0x0501 => DecoderError::IntegrityCheck,
+            0x0601 => DecoderError::Panic,
+            _ => return None,
+        })
+    }
+}
+
+impl From<FlashError> for DecoderError {
+    fn from(err: FlashError) -> DecoderError {
+        DecoderError::Flash(err)
+    }
+}
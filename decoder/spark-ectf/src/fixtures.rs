@@ -0,0 +1,391 @@
+//! Secrets, subscriptions and frames made the same way gen_secrets, gen_subscription and the encoder make them,
+//! so the tests can drive the decoder on the host like a TV would.
+
+use crate::command::{Commands, Context};
+use crate::console::{ack, error_body, read_resp, Session};
+use crate::error::DecoderError;
+use crate::host::{ConsoleClosed, HostDelay, HostRng, PipeIo, RamFlash, StreamConsole};
+use crate::hw::{ByteIo, Flash, FlashError};
+use crate::protocol::{MessageHeader, Opcode, BLOCK_SIZE, HEADER_SIZE};
+use crate::replay::{ReplayGuard, ReplayPolicy};
+use crate::subscription::Subscription;
+use crate::subscription_log::SubscriptionLog;
+use crate::{load_subscription, load_subscriptions, Aes128Ofb, Board, DeviceInfo, DeviceKeys, DEVICE_ID_LOC, INTERMEDIATE_LOC, INTERMEDIATE_NUM,
+    INTERMEDIATE_SIZE, SUBSCRIPTION_CONTEXT, SUBSCRIPTION_SIZE};
+use ed25519_dalek::{Digest, Sha512, SigningKey, VerifyingKey};
+use ofb::cipher::{KeyIvInit, StreamCipher};
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::thread::{self, JoinHandle};
+use std::vec;
+use std::vec::Vec;
+
+/// The DECODER_ID the test decoder is built with
+pub const DECODER_ID: u32 = 0xdead_beef;
+
+/// A decoder whose console throws away everything written to it
+pub type TestBoard = Board<StreamConsole<Cursor<Vec<u8>>>, RamFlash, HostRng, HostDelay>;
+
+/// A decoder talking to a Tv
+pub type TvBoard = Board<PipeIo, RamFlash, HostRng, HostDelay>;
+
+/// The secrets of a deployment, generated from a fixed seed so every run sees the same ones
+pub struct Secrets {
+    channels: Vec<u32>,
+    /// The forward and backward root of every channel, the emergency channel included
+    roots: BTreeMap<u32, (u128, u128)>,
+    /// The AES key and IV every channel's intermediates are encrypted with
+    keys: BTreeMap<u32, [u8; 32]>,
+    signer: SigningKey,
+}
+
+impl Secrets {
+    /// Generates the secrets for a list of channels
+    /// @param channels The channels, without the emergency channel
+    /// @return The secrets
+    pub fn new(channels: &[u32]) -> Secrets {
+        let mut seed = 0x1234_5678_9abc_def0u64;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let mut roots = BTreeMap::new();
+        let mut keys = BTreeMap::new();
+        for &channel in [0].iter().chain(channels) {
+            roots.insert(channel, (((next() as u128) << 64) | next() as u128, ((next() as u128) << 64) | next() as u128));
+            let mut key = [0u8; 32];
+            for chunk in key.chunks_mut(8) {
+                chunk.copy_from_slice(&next().to_be_bytes());
+            }
+            keys.insert(channel, key);
+        }
+        let mut signer = [0u8; 32];
+        for chunk in signer.chunks_mut(8) {
+            chunk.copy_from_slice(&next().to_be_bytes());
+        }
+        Secrets { channels: channels.to_vec(), roots, keys, signer: SigningKey::from_bytes(&signer) }
+    }
+
+    /// Builds what build.py bakes into the firmware: keys.bin, emergency.bin and the channel list
+    /// @return The device keys, leaked since a decoder keeps them for its whole run
+    pub fn device_keys(&self) -> DeviceKeys {
+        let mut keys = self.keys[&0].to_vec();
+        let mut channels = [0u32; 17];
+        for (slot, channel) in channels.iter_mut().skip(1).zip(&self.channels) {
+            keys.extend_from_slice(&self.keys[channel]);
+            *slot = *channel;
+        }
+        DeviceKeys {
+            keys: Vec::leak(keys),
+            emergency: Vec::leak(self.subscription(0, 0, u64::MAX)),
+            channels,
+        }
+    }
+
+    /// Gets the key frames and subscriptions are verified with, as in public.bin
+    /// @return The verifying key
+    pub fn verifier(&self) -> VerifyingKey {
+        self.signer.verifying_key()
+    }
+
+    /// Makes a signed subscription for the test decoder, laid out as gen_subscription lays it out
+    /// @param channel The channel ID
+    /// @param start The first timestamp of the subscription
+    /// @param end The last timestamp of the subscription
+    /// @return The subscription followed by its signature
+    pub fn subscription(&self, channel: u32, start: u64, end: u64) -> Vec<u8> {
+        self.subscription_for(DECODER_ID, channel, start, end)
+    }
+
+    /// Makes a signed subscription for any decoder of the deployment
+    /// @param decoder_id The DECODER_ID of the decoder the subscription is for
+    /// @param channel The channel ID
+    /// @param start The first timestamp of the subscription
+    /// @param end The last timestamp of the subscription
+    /// @return The subscription followed by its signature
+    pub fn subscription_for(&self, decoder_id: u32, channel: u32, start: u64, end: u64) -> Vec<u8> {
+        let (forward, backward) = self.roots[&channel];
+        let forward = intermediates(start, end, forward);
+        let backward = intermediates(!end, !start, backward);
+        let mut ret = Vec::new();
+        ret.extend_from_slice(&channel.to_be_bytes());
+        ret.extend_from_slice(&start.to_be_bytes());
+        ret.extend_from_slice(&end.to_be_bytes());
+        ret.extend_from_slice(&[forward.len() as u8, backward.len() as u8]);
+        for side in [&forward, &backward] {
+            let mut positions = vec![0u8; INTERMEDIATE_NUM * 8];
+            for (chunk, position) in positions.chunks_mut(8).zip(side.keys()) {
+                chunk.copy_from_slice(&position.to_be_bytes());
+            }
+            ret.extend(positions);
+        }
+        ret.resize(DEVICE_ID_LOC, 0);
+        ret.extend_from_slice(&decoder_id.to_be_bytes());
+        ret.resize(INTERMEDIATE_LOC as usize, 0);
+        let key = self.keys[&channel];
+        for side in [&forward, &backward] {
+            let mut encrypted = vec![0u8; INTERMEDIATE_NUM * INTERMEDIATE_SIZE];
+            for (chunk, intermediate) in encrypted.chunks_mut(INTERMEDIATE_SIZE).zip(side.values()) {
+                chunk.copy_from_slice(&intermediate.to_be_bytes());
+                Aes128Ofb::new((&key[..16]).into(), (&key[16..]).into()).apply_keystream(chunk);
+            }
+            ret.extend(encrypted);
+        }
+        assert_eq!(ret.len(), SUBSCRIPTION_SIZE);
+        let signature = self.signer.sign_prehashed(Sha512::new().chain_update(&ret), Some(SUBSCRIPTION_CONTEXT)).unwrap();
+        ret.extend_from_slice(&signature.to_bytes());
+        ret
+    }
+
+    /// Encodes a frame the way the encoder does
+    /// @param channel The channel ID
+    /// @param frame The frame
+    /// @param timestamp The timestamp of the frame
+    /// @return The frame packet sent in a DECODE message
+    pub fn encode(&self, channel: u32, frame: &[u8], timestamp: u64) -> Vec<u8> {
+        let (forward, backward) = self.roots[&channel];
+        let mut guard = [0u8; 64];
+        guard[48..].copy_from_slice(&(wind(forward, timestamp) ^ wind(backward, !timestamp)).to_be_bytes());
+        let mut keystream = vec![0u8; frame.len()];
+        blake3::Hasher::new().update(&guard).update(&Subscription::BIG_BYTES).finalize_xof().fill(&mut keystream);
+
+        let length = (frame.len() as u16).to_be_bytes();
+        let digest = Sha512::new().chain_update(length).chain_update(frame);
+        let signature = self.signer.sign_prehashed(digest, Some(&channel.to_be_bytes())).unwrap();
+        let mut ret = Vec::new();
+        ret.extend_from_slice(&channel.to_be_bytes());
+        ret.extend_from_slice(&timestamp.to_be_bytes());
+        ret.extend_from_slice(&length);
+        ret.extend_from_slice(&signature.to_bytes());
+        ret.extend(frame.iter().zip(keystream).map(|(byte, key)| byte ^ key));
+        ret
+    }
+}
+
+/// Hashes a root down to the key for a position, one bit of the position at a time
+/// @param root The root of one side of a channel
+/// @param position The timestamp, or its inverse for the backward side
+/// @return The key for the position
+fn wind(root: u128, position: u64) -> u128 {
+    (0..64).rev().filter(|bit| position & (1 << bit) != 0).fold(root, |key, bit| Subscription::hash(key, bit as u8))
+}
+
+/// Picks the intermediates that cover a range of positions, as gen_subscription does
+/// @param start The first position
+/// @param end The last position
+/// @param root The root of the side
+/// @return The intermediate for each position it starts at
+fn intermediates(start: u64, end: u64, root: u128) -> BTreeMap<u64, u128> {
+    let mut ret = BTreeMap::new();
+    if start == 0 {
+        ret.insert(0, root);
+        return ret;
+    }
+    let mut position = start as u128;
+    while position <= end as u128 {
+        ret.insert(position as u64, wind(root, position as u64));
+        position += 1 << (position as u64).trailing_zeros();
+    }
+    ret
+}
+
+/// A flash that counts its writes and erases, and can be cut off after a number of them like a reset would
+pub struct CountingFlash {
+    pub flash: RamFlash,
+    pub writes: usize,
+    pub erases: usize,
+    /// How many more writes and erases go through, or None for no limit
+    pub budget: Option<usize>,
+}
+
+impl CountingFlash {
+    pub fn new() -> CountingFlash {
+        CountingFlash { flash: RamFlash::new(), writes: 0, erases: 0, budget: None }
+    }
+
+    /// Takes one write or erase out of the budget
+    /// @return Whether it goes through
+    fn spend(&mut self) -> Result<(), FlashError> {
+        match self.budget {
+            Some(0) => Err(FlashError::AccessViolation),
+            Some(budget) => {
+                self.budget = Some(budget - 1);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Flash for CountingFlash {
+    fn check_address(&self, address: u32) -> Result<(), FlashError> {
+        self.flash.check_address(address)
+    }
+
+    fn read_128(&self, address: u32) -> Result<[u32; 4], FlashError> {
+        self.flash.read_128(address)
+    }
+
+    fn write_128(&mut self, address: u32, data: &[u32; 4]) -> Result<(), FlashError> {
+        self.spend()?;
+        self.writes += 1;
+        self.flash.write_128(address, data)
+    }
+
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError> {
+        self.spend()?;
+        self.erases += 1;
+        self.flash.erase_page(address)
+    }
+}
+
+/// Builds a freshly flashed test decoder with an erased flash
+/// @param secrets The secrets the decoder is built with
+/// @return The board
+pub fn board(secrets: &Secrets) -> TestBoard {
+    board_with(secrets, StreamConsole::new(Cursor::new(Vec::new())))
+}
+
+/// Builds a freshly flashed test decoder with an erased flash, talking through a given console
+/// @param secrets The secrets the decoder is built with
+/// @param console The console the TV talks through
+/// @return The board
+pub fn board_with<C: ByteIo>(secrets: &Secrets, console: C) -> Board<C, RamFlash, HostRng, HostDelay> {
+    Board {
+        console,
+        flash: RamFlash::new(),
+        trng: HostRng::new(1),
+        delay: HostDelay,
+        keys: secrets.device_keys(),
+        info: DeviceInfo::parse(DECODER_ID, "1.0.0", "", "64"),
+    }
+}
+
+/// Stores a subscription and loads it into the live table, as SUBSCRIBE does once the subscription checks out
+/// @param board The board, holding the flash
+/// @param subscriptions The subscription list
+/// @param replay The replay state the subscription picks up
+/// @param signed The subscription followed by its signature
+pub fn install(board: &mut TestBoard, subscriptions: &mut [Option<Subscription>; 9], replay: &ReplayGuard, signed: &[u8]) {
+    let channel = u32::from_be_bytes(signed[0..4].try_into().unwrap());
+    let slot = crate::get_subscription_for_channel(channel, subscriptions).unwrap() as usize;
+    let mut log = SubscriptionLog::load(&mut board.flash, board.keys.storage_key());
+    let address = log.write(&mut board.flash, channel, &signed[..SUBSCRIPTION_SIZE]).unwrap();
+    log.relocate(subscriptions);
+    let mut sub = load_subscription(board, address).unwrap();
+    replay.restore(&mut sub);
+    subscriptions[slot] = Some(sub);
+}
+
+/// The TV end of the link, which talks to the decoder the way the host tools do
+pub struct Tv {
+    pub link: PipeIo,
+    /// How many blocks of the last command's body the decoder never asked for
+    pub unsent: usize,
+}
+
+impl Tv {
+    /// Boots a decoder with an erased flash on another thread, running its command loop until the link closes
+    /// and then handing the board back
+    pub fn boot(secrets: &Secrets) -> (Tv, JoinHandle<TvBoard>) {
+        Tv::boot_with(secrets, 64)
+    }
+
+    /// Boots a decoder the same way, built to take frames of up to a given size
+    pub fn boot_with(secrets: &Secrets, max_frame_size: u16) -> (Tv, JoinHandle<TvBoard>) {
+        let (link, console) = PipeIo::pair();
+        let mut board = board_with(secrets, console);
+        board.info.max_frame_size = max_frame_size;
+        let verifier = secrets.verifier();
+        let decoder = thread::spawn(move || {
+            let mut subscriptions = load_subscriptions(&mut board);
+            let mut replay = ReplayGuard::load(ReplayPolicy::StrictPerChannel, &mut board.flash);
+            let mut session = Session::default();
+            let mut ctx = Context {
+                board: &mut board,
+                subscriptions: &mut subscriptions,
+                replay: &mut replay,
+                verifier,
+                session: &mut session,
+            };
+            let commands = Commands::default();
+            let Err(payload) = catch_unwind(AssertUnwindSafe(|| loop {
+                read_resp(&mut ctx, &commands);
+            }));
+            assert!(payload.is::<ConsoleClosed>());
+            board
+        });
+        (Tv { link, unsent: 0 }, decoder)
+    }
+
+    /// Receives the next message from the decoder, skipping debug messages
+    /// @param acked Whether the link is in lock-step, so the message is acknowledged
+    /// @return An ACK, or the opcode and body of the message
+    pub fn receive(&mut self, acked: bool) -> (Opcode, Vec<u8>) {
+        loop {
+            let mut header = [0u8; HEADER_SIZE];
+            header.iter_mut().for_each(|byte| *byte = self.link.read_byte());
+            let header = MessageHeader::decode(&header).unwrap();
+            if !acked || !header.opcode.is_acked() {
+                let body: Vec<u8> = (0..header.length).map(|_| self.link.read_byte()).collect();
+                if header.opcode == Opcode::Debug {
+                    continue;
+                }
+                return (header.opcode, body);
+            }
+            let mut body = Vec::new();
+            ack(&mut self.link);
+            while body.len() < header.length as usize {
+                let block = BLOCK_SIZE.min(header.length as usize - body.len());
+                body.extend((0..block).map(|_| self.link.read_byte()));
+                ack(&mut self.link);
+            }
+            return (header.opcode, body);
+        }
+    }
+
+    /// Sends a command a block at a time as each ACK comes in
+    /// @return The answer to the command
+    pub fn command(&mut self, opcode: Opcode, body: &[u8]) -> (Opcode, Vec<u8>) {
+        self.link.write_bytes(&MessageHeader::new(opcode, body.len() as u16).encode());
+        let mut blocks = body.chunks(BLOCK_SIZE);
+        loop {
+            match self.receive(true) {
+                (Opcode::Ack, _) => {
+                    if let Some(block) = blocks.next() {
+                        self.link.write_bytes(block);
+                    }
+                }
+                answer => {
+                    self.unsent = blocks.len();
+                    return answer;
+                }
+            }
+        }
+    }
+
+    /// Sends a whole message without waiting for anything, as streaming mode does
+    pub fn send(&mut self, opcode: Opcode, body: &[u8]) {
+        self.link.write_bytes(&MessageHeader::new(opcode, body.len() as u16).encode());
+        self.link.write_bytes(body);
+    }
+}
+
+/// Builds the answer to a command that failed
+pub fn error(err: DecoderError) -> (Opcode, Vec<u8>) {
+    (Opcode::Error, error_body(err))
+}
+
+/// Builds the answer to LIST
+pub fn listing(subscriptions: &[(u32, u64, u64)]) -> (Opcode, Vec<u8>) {
+    let mut body = (subscriptions.len() as u32).to_le_bytes().to_vec();
+    for (channel, start, end) in subscriptions {
+        body.extend_from_slice(&channel.to_le_bytes());
+        body.extend_from_slice(&start.to_le_bytes());
+        body.extend_from_slice(&end.to_le_bytes());
+    }
+    (Opcode::List, body)
+}
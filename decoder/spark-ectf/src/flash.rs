@@ -0,0 +1,62 @@
+use crate::error::DecoderError;
+use crate::hw::Flash;
+
+/// @param flash The flash to read from
+/// @param frm The address of the bytes to be read
+/// @param dst The reference to the data's destination
+/// @param len The size of the bytes to be read
+/// @return An error or nothing on success
+pub fn read_bytes<F: Flash>(flash: &F, frm: u32, dst: &mut [u8], len: usize) -> Result<(), DecoderError> {
+    // Checks that the slice has enough space
+    if dst.len() < len {
+        return Err(DecoderError::BufferTooSmall);
+    }
+    // Reads values 128 bits at a time
+    for i in 0..len.div_ceil(16) {
+        let addr_128_ptr = frm + (i * 16) as u32;
+        // Collects the result and checks it for errors
+        let words = flash.read_128(addr_128_ptr).map_err(DecoderError::Flash)?;
+        // Assigns the result to the correct value, cutting the last word short if needed
+        for (j, word) in words.iter().enumerate() {
+            for (k, byte) in word.to_le_bytes().into_iter().enumerate() {
+                let pos = i * 16 + j * 4 + k;
+                if pos < len {
+                    dst[pos] = byte;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes bytes to the flash
+/// @param flash The flash to write to
+/// @param dst A u32 representing the start address of the write location in flash memory
+/// @param from The slice of bytes being written
+/// @param len The length of the bytes that will be written
+/// @return Either nothing, or the error
+pub fn write_bytes<F: Flash>(flash: &mut F, dst: u32, from: &[u8], len: usize) -> Result<(), DecoderError> {
+    if from.len() < len {
+        return Err(DecoderError::BufferTooSmall);
+    }
+
+    for i in 0..len.div_ceil(16) {
+        // For 128-bit addresses, leaving any bytes past the end erased
+        let addr_128_ptr = dst + (i * 16) as u32;
+        let mut bytes: [u32; 4] = [0xFFFFFFFF; 4];
+        for (j, word) in bytes.iter_mut().enumerate() {
+            let mut word_bytes = [0xFFu8; 4];
+            for (k, byte) in word_bytes.iter_mut().enumerate() {
+                let pos = i * 16 + j * 4 + k;
+                if pos < len {
+                    *byte = from[pos];
+                }
+            }
+            *word = u32::from_le_bytes(word_bytes);
+        }
+        // Performs write and checks for errors
+        flash.write_128(addr_128_ptr, &bytes).map_err(DecoderError::Flash)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,343 @@
+//! Host implementations of the hardware traits, so the decoder logic can run on Linux.
+
+use crate::hw::{ByteIo, Delay, Flash, FlashError, Rng};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::string::String;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::time::Duration;
+use std::vec;
+use std::vec::Vec;
+
+/// The address range and page size of the MAX78000 flash
+pub const FLASH_BASE: u32 = 0x1000_0000;
+pub const FLASH_SIZE: u32 = 0x0008_0000;
+pub const FLASH_PAGE_SIZE: u32 = 0x2000;
+
+/// How long a non-blocking stream is left before it is checked again
+const POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+/// The panic payload raised by StreamConsole once its stream is closed, so a host can wait for the next connection
+#[derive(Debug)]
+pub struct ConsoleClosed;
+
+/// A console over any byte stream, such as a TCP socket, a pseudo-terminal or an in-memory buffer
+/// Reads only time out on a non-blocking stream, since a blocking one can't tell that nothing has arrived.
+pub struct StreamConsole<S> {
+    stream: S,
+}
+
+impl<S: Read + Write> StreamConsole<S> {
+    pub fn new(stream: S) -> StreamConsole<S> {
+        StreamConsole { stream }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Read + Write> ByteIo for StreamConsole<S> {
+    /// Reads a byte, panicking with ConsoleClosed once the stream is closed since the command loop has nothing left to do.
+    fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read_byte() {
+                return byte;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn try_read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0u8; 1];
+        loop {
+            match self.stream.read(&mut byte) {
+                Ok(1) => return Some(byte[0]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                _ => std::panic::panic_any(ConsoleClosed),
+            }
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.write_bytes(&[byte]);
+    }
+
+    fn write_bytes(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            match self.stream.write(bytes) {
+                Ok(0) => std::panic::panic_any(ConsoleClosed),
+                Ok(written) => bytes = &bytes[written..],
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => std::thread::sleep(POLL_INTERVAL),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => std::panic::panic_any(ConsoleClosed),
+            }
+        }
+        if self.stream.flush().is_err() {
+            std::panic::panic_any(ConsoleClosed);
+        }
+    }
+
+    /// Sockets and terminals are buffered by the OS, which holds far more than any streaming window.
+    fn rx_capacity(&self) -> usize {
+        64 * 1024
+    }
+}
+
+/// One end of an in-memory link, so a test can play the TV on one thread while the command loop runs on another
+/// Dropping either end closes the link, which the other end sees as ConsoleClosed.
+pub struct PipeIo {
+    rx: Receiver<u8>,
+    tx: Sender<u8>,
+}
+
+impl PipeIo {
+    /// Creates both ends of a link
+    pub fn pair() -> (PipeIo, PipeIo) {
+        let (a_tx, a_rx) = channel();
+        let (b_tx, b_rx) = channel();
+        (PipeIo { rx: a_rx, tx: b_tx }, PipeIo { rx: b_rx, tx: a_tx })
+    }
+}
+
+impl ByteIo for PipeIo {
+    fn read_byte(&mut self) -> u8 {
+        self.rx.recv().unwrap_or_else(|_| std::panic::panic_any(ConsoleClosed))
+    }
+
+    fn try_read_byte(&mut self) -> Option<u8> {
+        match self.rx.try_recv() {
+            Ok(byte) => Some(byte),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => std::panic::panic_any(ConsoleClosed),
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        if self.tx.send(byte).is_err() {
+            std::panic::panic_any(ConsoleClosed);
+        }
+    }
+
+    /// The channel holds everything sent until it is read.
+    fn rx_capacity(&self) -> usize {
+        64 * 1024
+    }
+}
+
+/// A flash image held in memory, which follows the same write and erase rules as the real one
+pub struct RamFlash {
+    image: Vec<u8>,
+}
+
+impl RamFlash {
+    /// Creates a fully erased flash.
+    pub fn new() -> RamFlash {
+        RamFlash { image: vec![0xFF; FLASH_SIZE as usize] }
+    }
+
+    /// Creates a flash from an existing image, padding it out with erased bytes.
+    pub fn from_image(mut image: Vec<u8>) -> RamFlash {
+        image.resize(FLASH_SIZE as usize, 0xFF);
+        RamFlash { image }
+    }
+
+    /// Gets the whole flash image, starting at FLASH_BASE.
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    /// Checks that an address is 128-bit aligned and within the flash, and turns it into an offset in the image.
+    fn offset_128(&self, address: u32) -> Result<usize, FlashError> {
+        if address & 0b1111 != 0 {
+            return Err(FlashError::InvalidAddress);
+        }
+        self.check_address(address)?;
+        Ok((address - FLASH_BASE) as usize)
+    }
+}
+
+impl Default for RamFlash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Flash for RamFlash {
+    fn check_address(&self, address: u32) -> Result<(), FlashError> {
+        if !(FLASH_BASE..FLASH_BASE + FLASH_SIZE).contains(&address) {
+            return Err(FlashError::InvalidAddress);
+        }
+        Ok(())
+    }
+
+    fn read_128(&self, address: u32) -> Result<[u32; 4], FlashError> {
+        let offset = self.offset_128(address)?;
+        let mut words = [0u32; 4];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = u32::from_le_bytes(self.image[offset + i * 4..offset + i * 4 + 4].try_into().unwrap());
+        }
+        Ok(words)
+    }
+
+    fn write_128(&mut self, address: u32, data: &[u32; 4]) -> Result<(), FlashError> {
+        let offset = self.offset_128(address)?;
+        // Like the real flash, bits can only be cleared until the page is erased
+        let old = self.read_128(address)?;
+        if old.iter().zip(data).any(|(old, new)| old & new != *new) {
+            return Err(FlashError::NeedsErase);
+        }
+        for (i, word) in data.iter().enumerate() {
+            self.image[offset + i * 4..offset + i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError> {
+        self.check_address(address)?;
+        let page = ((address - FLASH_BASE) & !(FLASH_PAGE_SIZE - 1)) as usize;
+        self.image[page..page + FLASH_PAGE_SIZE as usize].fill(0xFF);
+        Ok(())
+    }
+}
+
+/// A flash image kept in a file, so subscriptions and replay state survive restarting the host
+pub struct FileFlash {
+    ram: RamFlash,
+    file: File,
+}
+
+impl FileFlash {
+    /// Opens a flash image, creating a fully erased one if the file doesn't exist yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileFlash> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut image = Vec::new();
+        file.read_to_end(&mut image)?;
+        let ram = RamFlash::from_image(image);
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(ram.image())?;
+        Ok(FileFlash { ram, file })
+    }
+
+    /// Writes part of the image back to the file.
+    fn sync(&mut self, offset: usize, len: usize) -> Result<(), FlashError> {
+        self.file.seek(SeekFrom::Start(offset as u64))
+            .and_then(|_| self.file.write_all(&self.ram.image()[offset..offset + len]))
+            .map_err(|_| FlashError::AccessViolation)
+    }
+}
+
+impl Flash for FileFlash {
+    fn check_address(&self, address: u32) -> Result<(), FlashError> {
+        self.ram.check_address(address)
+    }
+
+    fn read_128(&self, address: u32) -> Result<[u32; 4], FlashError> {
+        self.ram.read_128(address)
+    }
+
+    fn write_128(&mut self, address: u32, data: &[u32; 4]) -> Result<(), FlashError> {
+        self.ram.write_128(address, data)?;
+        self.sync((address - FLASH_BASE) as usize, 16)
+    }
+
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError> {
+        self.ram.erase_page(address)?;
+        self.sync(((address - FLASH_BASE) & !(FLASH_PAGE_SIZE - 1)) as usize, FLASH_PAGE_SIZE as usize)
+    }
+}
+
+/// The master side of a pseudo-terminal, which serial tools can open through its slave path
+pub struct Pty {
+    master: File,
+    slave_path: String,
+}
+
+impl Pty {
+    /// Opens a new pseudo-terminal in raw, non-blocking mode.
+    pub fn open() -> io::Result<Pty> {
+        use std::ffi::CStr;
+        use std::os::fd::FromRawFd;
+        // Security guarantee: Every call is checked, and the descriptor is only wrapped once it's valid
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master = File::from_raw_fd(fd);
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 || libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut termios: libc::termios = core::mem::zeroed();
+            if libc::tcgetattr(fd, &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut name = [0 as libc::c_char; 128];
+            if libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let slave_path = CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+            Ok(Pty { master, slave_path })
+        }
+    }
+
+    /// Gets the path serial tools should open, like /dev/pts/3.
+    pub fn slave_path(&self) -> &str {
+        &self.slave_path
+    }
+}
+
+impl Read for Pty {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.master.read(buf)
+    }
+}
+
+impl Write for Pty {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.master.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.master.flush()
+    }
+}
+
+/// A xorshift generator standing in for the TRNG
+pub struct HostRng {
+    state: u32,
+}
+
+impl HostRng {
+    pub fn new(seed: u32) -> HostRng {
+        HostRng { state: seed.max(1) }
+    }
+}
+
+impl Rng for HostRng {
+    fn gen_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+}
+
+/// A delay that sleeps the current thread
+pub struct HostDelay;
+
+impl Delay for HostDelay {
+    fn delay_us(&mut self, us: u32) {
+        std::thread::sleep(Duration::from_micros(us as u64));
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        std::thread::sleep(Duration::from_millis(ms as u64));
+    }
+}
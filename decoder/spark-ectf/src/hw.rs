@@ -0,0 +1,124 @@
+//! The small pieces of hardware the decoder logic runs on.
+//! The firmware implements these for the MAX78000 peripherals, and the `host` module implements them for Linux.
+
+/// Failures reported by a flash implementation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashError {
+    InvalidAddress,
+    AccessViolation,
+    NeedsErase,
+}
+
+/// A serial link to the host, one byte at a time
+pub trait ByteIo {
+    /// Reads a byte, blocking until one arrives.
+    fn read_byte(&mut self) -> u8;
+
+    /// Reads a byte if one has already arrived, without blocking.
+    /// A link that can't tell just blocks, so reads from it never time out.
+    fn try_read_byte(&mut self) -> Option<u8> {
+        Some(self.read_byte())
+    }
+
+    /// Writes a byte.
+    fn write_byte(&mut self, byte: u8);
+
+    /// Writes a list of bytes.
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_byte(*byte);
+        }
+    }
+
+    /// Gets how many received bytes the link holds on to while the decoder is busy, which limits the streaming window.
+    fn rx_capacity(&self) -> usize {
+        0
+    }
+}
+
+/// Flash memory, written in 128-bit words and erased a page at a time
+pub trait Flash {
+    /// Checks that an address lies within the flash.
+    fn check_address(&self, address: u32) -> Result<(), FlashError>;
+
+    /// Reads one 128-bit aligned word.
+    fn read_128(&self, address: u32) -> Result<[u32; 4], FlashError>;
+
+    /// Writes one 128-bit aligned word, which must not need any bit to go from 0 to 1.
+    fn write_128(&mut self, address: u32, data: &[u32; 4]) -> Result<(), FlashError>;
+
+    /// Erases the page containing the address back to all 1s.
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError>;
+}
+
+/// A source of random numbers
+pub trait Rng {
+    fn gen_u32(&mut self) -> u32;
+}
+
+/// A blocking delay
+pub trait Delay {
+    fn delay_us(&mut self, us: u32);
+
+    fn delay_ms(&mut self, ms: u32) {
+        for _ in 0..ms {
+            self.delay_us(1000);
+        }
+    }
+}
+
+impl<T: ByteIo + ?Sized> ByteIo for &mut T {
+    fn read_byte(&mut self) -> u8 {
+        (**self).read_byte()
+    }
+
+    fn try_read_byte(&mut self) -> Option<u8> {
+        (**self).try_read_byte()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        (**self).write_byte(byte)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        (**self).write_bytes(bytes)
+    }
+
+    fn rx_capacity(&self) -> usize {
+        (**self).rx_capacity()
+    }
+}
+
+impl<T: Flash + ?Sized> Flash for &mut T {
+    fn check_address(&self, address: u32) -> Result<(), FlashError> {
+        (**self).check_address(address)
+    }
+
+    fn read_128(&self, address: u32) -> Result<[u32; 4], FlashError> {
+        (**self).read_128(address)
+    }
+
+    fn write_128(&mut self, address: u32, data: &[u32; 4]) -> Result<(), FlashError> {
+        (**self).write_128(address, data)
+    }
+
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError> {
+        (**self).erase_page(address)
+    }
+}
+
+impl<T: Rng + ?Sized> Rng for &mut T {
+    fn gen_u32(&mut self) -> u32 {
+        (**self).gen_u32()
+    }
+}
+
+impl<T: Delay + ?Sized> Delay for &mut T {
+    fn delay_us(&mut self, us: u32) {
+        (**self).delay_us(us)
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        (**self).delay_ms(ms)
+    }
+}
@@ -0,0 +1,404 @@
+//! The hardware-independent half of the Spark decoder.
+//! The firmware in `decoder` provides the MAX78000 peripherals through the traits in `hw`,
+//! while the `host` module (behind the `std` feature) lets the same command loop run on Linux.
+#![no_std]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+extern crate aes as encrypt_aes;
+
+pub mod command;
+pub mod console;
+pub mod error;
+#[cfg(all(test, feature = "std"))]
+mod fixtures;
+pub mod flash;
+#[cfg(feature = "std")]
+pub mod host;
+pub mod hw;
+pub mod protocol;
+pub mod replay;
+pub mod replay_log;
+pub mod subscription;
+pub mod subscription_log;
+
+use alloc::format;
+use crypto_bigint::U512;
+use ed25519_dalek::{Digest, DigestVerifier, Sha512, Signature, VerifyingKey};
+use ofb::cipher::{KeyIvInit, StreamCipher};
+use crate::console::{write_console, write_err};
+use crate::error::DecoderError;
+use crate::hw::{ByteIo, Delay, Flash, Rng};
+use crate::subscription::Subscription;
+use crate::subscription_log::SubscriptionLog;
+
+type Integer = U512;
+
+pub const SUB_SPACE: u32 = 8192; /* page length */
+pub const REQUIRED_MEMORY: u32 = 4 + 8 + 8 + 2 + (64 * 8 * 2);
+/* channel # + start + end + length checks + forward key indices + backward key indices */
+// Where the ID of the decoder a subscription was made for sits, right after the metadata
+pub const DEVICE_ID_LOC: usize = REQUIRED_MEMORY as usize;
+
+pub const INTERMEDIATE_NUM: usize = 64;
+pub const INTERMEDIATE_LOC: u32 = 1280;
+pub const INTERMEDIATE_SIZE: usize = 16;
+pub const INTERMEDIATE_POS_SIZE: usize = 8;
+// The size of a whole subscription: its metadata, then the forward and backward intermediates
+pub const SUBSCRIPTION_SIZE: usize = INTERMEDIATE_LOC as usize + 2 * INTERMEDIATE_NUM * INTERMEDIATE_SIZE;
+// The size of the Ed25519 signature gen_subscription puts after a subscription
+pub const SIGNATURE_SIZE: usize = 64;
+// The context subscriptions are signed with, which keeps their signatures apart from those on frames
+pub const SUBSCRIPTION_CONTEXT: &[u8] = b"subscription";
+
+// The location of the subscription log on the flash, whose SUB_PAGES end where the team firmware area does
+pub const SUB_LOC: u32 = 0x10038000;
+
+// The largest frame a build decodes unless it is configured otherwise
+pub const FRAME_SIZE: usize = 64;
+// The largest frame any build can be configured to decode
+pub const MAX_FRAME_SIZE: usize = 1024;
+// The part of an encoded frame packet ahead of the frame: channel, timestamp, frame length and signature
+pub const PACKET_OVERHEAD: usize = 4 + 8 + 2 + 64;
+// The size of the largest encoded frame packet
+pub const MAX_PACKET_SIZE: usize = PACKET_OVERHEAD + MAX_FRAME_SIZE;
+
+type Aes128Ofb = ofb::Ofb<encrypt_aes::Aes128>;
+
+/// The secrets and channel list a decoder is built with
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceKeys {
+    /// The AES key and IV of every channel, in the same order as `channels` (keys.bin)
+    pub keys: &'static [u8],
+    /// The emergency channel subscription (emergency.bin)
+    pub emergency: &'static [u8],
+    /// The channels the decoder was built for, with the emergency channel first
+    pub channels: [u32; 17],
+}
+
+impl DeviceKeys {
+    /// Derives the key of the MAC on subscriptions kept in flash from the channel keys, so it never leaves the decoder
+    /// @return The MAC key
+    pub fn storage_key(&self) -> [u8; 32] {
+        blake3::derive_key("spark-ectf 2025 stored subscription MAC", self.keys)
+    }
+}
+
+/// What a decoder build is, as reported to the host
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// The DECODER_ID the decoder was built with
+    pub decoder_id: u32,
+    /// The major, minor and patch version of the firmware
+    pub version: [u8; 3],
+    /// The commit the firmware was built from, or all zeroes if it isn't known
+    pub git_hash: [u8; 20],
+    /// The largest frame the decoder accepts, which is at most MAX_FRAME_SIZE
+    pub max_frame_size: u16,
+}
+
+impl DeviceInfo {
+    /// Builds the device information from the strings baked into a build
+    /// A version or hash that doesn't parse is reported as zero, and a frame size falls back to FRAME_SIZE
+    /// @param decoder_id The decoder ID, already parsed by parse_decoder_id since subscriptions are checked against it
+    /// @param version The firmware version, like 1.2.3
+    /// @param git_hash The full hex hash of the commit
+    /// @param max_frame_size The largest frame to accept, in bytes, which is capped at MAX_FRAME_SIZE
+    /// @return The device information
+    pub fn parse(decoder_id: u32, version: &str, git_hash: &str, max_frame_size: &str) -> DeviceInfo {
+        let max_frame_size = max_frame_size.parse().unwrap_or(FRAME_SIZE).min(MAX_FRAME_SIZE) as u16;
+        let mut info = DeviceInfo { decoder_id, max_frame_size, ..DeviceInfo::default() };
+        for (part, number) in info.version.iter_mut().zip(version.split('.')) {
+            *part = number.parse().unwrap_or(0);
+        }
+        if git_hash.len() == 2 * info.git_hash.len() {
+            for (i, byte) in info.git_hash.iter_mut().enumerate() {
+                *byte = git_hash.get(i * 2..i * 2 + 2).and_then(|hex| u8::from_str_radix(hex, 16).ok()).unwrap_or(0);
+            }
+        }
+        info
+    }
+}
+
+/// Parses a decoder ID the way the build is given it, in hex with a 0x prefix or in decimal
+/// It is a const fn, so the firmware fails to build with a DECODER_ID it would otherwise check subscriptions against as 0
+/// @param decoder_id The decoder ID
+/// @return The decoder ID, or nothing if it isn't a number that fits in 32 bits
+pub const fn parse_decoder_id(decoder_id: &str) -> Option<u32> {
+    let bytes = decoder_id.as_bytes();
+    let (radix, mut i) = if bytes.len() > 2 && bytes[0] == b'0' && (bytes[1] == b'x' || bytes[1] == b'X') {
+        (16, 2)
+    } else {
+        (10, 0)
+    };
+    if i == bytes.len() {
+        return None;
+    }
+    let mut value: u32 = 0;
+    while i < bytes.len() {
+        let digit = match bytes[i] {
+            b'0'..=b'9' => bytes[i] - b'0',
+            b'a'..=b'f' => bytes[i] - b'a' + 10,
+            b'A'..=b'F' => bytes[i] - b'A' + 10,
+            _ => return None,
+        } as u32;
+        if digit >= radix {
+            return None;
+        }
+        value = match value.checked_mul(radix) {
+            Some(value) => value,
+            None => return None,
+        };
+        value = match value.checked_add(digit) {
+            Some(value) => value,
+            None => return None,
+        };
+        i += 1;
+    }
+    Some(value)
+}
+
+/// Everything the decoder uses from the board it runs on
+pub struct Board<C, F, R, D> {
+    pub console: C,
+    pub flash: F,
+    pub trng: R,
+    pub delay: D,
+    pub keys: DeviceKeys,
+    pub info: DeviceInfo,
+}
+
+/// This function is used where the risk of serious data corruption is high, thereby allowing us to detect interference
+/// @param board The board, whose TRNG and delay give time for attacks to disrupt the data
+/// @return A value indicating success or failure
+pub fn test<C: ByteIo, F: Flash, R: Rng, D: Delay>(board: &mut Board<C, F, R, D>) -> bool {
+    match check_integrity(board) {
+        Ok(()) => true,
+        Err(err) => {
+            write_err(&mut board.console, &mut board.delay, err);
+            false
+        }
+    }
+}
+
+/// Runs the same check as test, but leaves reporting a failure to the caller
+/// @param board The board, whose TRNG and delay give time for attacks to disrupt the data
+/// @return Nothing, or the integrity error once the penalty delay has passed
+pub fn check_integrity<C, F, R: Rng, D: Delay>(board: &mut Board<C, F, R, D>) -> Result<(), DecoderError> {
+    let test_val = board.trng.gen_u32();
+    let output = test_2(test_val, board);
+    if test_val.wrapping_mul(test_val) == output {
+        Ok(())
+    } else {
+        board.delay.delay_ms(4500);
+        Err(DecoderError::IntegrityCheck)
+    }
+}
+
+/// Subroutine that performs the delayed calculation
+/// Refer to pub fn test just above this
+fn test_2<C, F, R: Rng, D: Delay>(scan: u32, board: &mut Board<C, F, R, D>) -> u32 {
+    let ret = scan;
+    let wait = 5u32 + (board.trng.gen_u32() & 255);
+    board.delay.delay_us(wait);
+    ret.wrapping_mul(ret)
+}
+
+///Reads all subscriptions from the flash
+///Acts as a wrapper to load_subscription
+///@param board The board, holding the flash system
+///@return A list of possible subscriptions
+pub fn load_subscriptions<C: ByteIo, F: Flash, R, D: Delay>(board: &mut Board<C, F, R, D>) -> [Option<Subscription>; 9] {
+    // Each subscription is the metadata (channel, start, end, lengths and positions), then the intermediates
+    let mut ret: [Option<Subscription>; 9] = [None; 9];
+
+    let log = SubscriptionLog::load(&mut board.flash, board.keys.storage_key());
+    // A record that fails its MAC leaves its channel with no subscription, which INFO reports to the host too
+    for channel in log.corrupt() {
+        write_console(&mut board.console, format!("Subscription for channel {} is corrupt and was dropped", channel).as_bytes());
+    }
+    for ((_, address), sub) in log.entries().zip(ret.iter_mut().skip(1)) {
+        *sub = load_subscription(board, address);
+    }
+    ret[0] = load_emergency_subscription(board);
+    ret
+}
+
+/// Reads a non-emergency subscription from the flash
+/// Reports errors to the console
+/// @param board The board, holding the flash system
+/// @param address Where the subscription log keeps the subscription
+/// @return The potential subscription now loaded into memory
+pub fn load_subscription<C: ByteIo, F: Flash, R, D: Delay>(board: &mut Board<C, F, R, D>, address: u32) -> Option<Subscription> {
+    let mut subscription: Subscription = Subscription::new();
+    let mut cache: [u8; 2048] = [0; 2048];
+
+    // Ensures that the address is valid
+    if let Err(err) = board.flash.check_address(address) {
+        write_err(&mut board.console, &mut board.delay, err.into());
+        return None
+    }
+    let _ = flash::read_bytes(&board.flash, address, &mut cache, REQUIRED_MEMORY as usize);
+
+    if !is_loadable(&cache) {
+        return None;
+    }
+    subscription.location = address as usize;
+    read_metadata(&mut subscription, &cache);
+    Some(subscription)
+}
+
+/// Verifies the signature gen_subscription put on a subscription, which covers every byte of it
+/// @param board The board, whose TRNG and delay drive the integrity check
+/// @param verifier The verifying key, which is the same one frames are checked with
+/// @param signed The subscription followed by its signature
+/// @return Nothing, or why the subscription can't be trusted
+pub fn verify_subscription<C, F, R: Rng, D: Delay>(board: &mut Board<C, F, R, D>, verifier: VerifyingKey, signed: &[u8])
+ -> Result<(), DecoderError> {
+    if signed.len() != SUBSCRIPTION_SIZE + SIGNATURE_SIZE {
+        return Err(DecoderError::BadLength);
+    }
+    let (subscription, signature) = signed.split_at(SUBSCRIPTION_SIZE);
+    let signature = Signature::from_slice(signature).map_err(|_| DecoderError::SubscriptionSignature)?;
+    let digest = Sha512::default().chain_update(subscription);
+
+    check_integrity(board)?;
+    let verifier_context = verifier.with_context(SUBSCRIPTION_CONTEXT).unwrap();
+    verifier_context.verify_digest(digest, &signature).map_err(|_| DecoderError::SubscriptionSignature)?;
+    check_integrity(board)
+}
+
+/// Checks that a subscription looks like one gen_subscription made, before it is trusted
+/// @param metadata The first REQUIRED_MEMORY bytes of the subscription
+/// @return Whether the subscription can be loaded
+pub fn is_loadable(metadata: &[u8]) -> bool {
+    let init = metadata[20]; // Should always be non-zero if it's loaded right
+    init != 0 && init != 0xFF
+}
+
+/// Fills in a subscription from its metadata, as laid out by gen_subscription
+/// @param subscription The subscription to fill in
+/// @param cache The first REQUIRED_MEMORY bytes of the subscription
+fn read_metadata(subscription: &mut Subscription, cache: &[u8]) {
+    let mut pos = 0;
+    subscription.channel = u32::from_be_bytes(cache[pos..pos+4].try_into().unwrap());
+    pos += 4;
+
+    subscription.start = u64::from_be_bytes(cache[pos..pos+8].try_into().unwrap());
+    pos += 8;
+    subscription.end = u64::from_be_bytes(cache[pos..pos+8].try_into().unwrap());
+    pos += 8;
+
+    pos += 2; // Lengths
+
+    for j in 0..64 {
+        let val = u64::from_be_bytes(cache[pos + j*8 ..pos + j*8 + 8].try_into().unwrap());
+        if val == 0 && j > 0 {
+            break;
+        }
+        subscription.forward_pos[j] = val;
+    }
+    pos += INTERMEDIATE_POS_SIZE * INTERMEDIATE_NUM;
+
+    for j in 0..64 {
+        let val = u64::from_be_bytes(cache[pos + j*8 ..pos + j*8 + 8].try_into().unwrap());
+        if val == 0 && j > 0 {
+            break;
+        }
+        subscription.backward_pos[j] = val;
+    }
+}
+
+/// Converts an intermediate in flash to the actual intermediate using AES
+/// @param keys The device keys
+/// @param encrypted_int The encrypted intermediate
+/// @param channel The channel ID of the intermediate
+/// @return The decrypted intermediate
+pub fn decrypt_intermediate(keys: &DeviceKeys, encrypted_int: u128, channel: u32) -> u128 {
+    // Get the right AES key by getting the right channel
+    let channel_pos = get_decrypt_loc_for_channel(keys, channel);
+    let mut copy = u128::to_be_bytes(encrypted_int);
+    let pos = (channel_pos * 32) as usize;
+
+    // Separate out the different parts of the key
+    let key: [u8; 16] = keys.keys[pos.. pos + 16].try_into().unwrap();
+    let iv: [u8; 16] = keys.keys[pos + 16.. pos + 32].try_into().unwrap();
+
+    // Initialize the cipher, decode the key, and return it
+    let mut cipher = Aes128Ofb::new(&key.into(), &iv.into());
+    cipher.apply_keystream(&mut copy);
+    u128::from_be_bytes(copy)
+}
+
+/// Loads the one emergency subscription from program memory
+/// @param board The board, holding the device keys
+/// @return The emergency subscription, if it's valid, else None
+pub fn load_emergency_subscription<C: ByteIo, F, R, D>(board: &mut Board<C, F, R, D>) -> Option<Subscription> {
+    let mut subscription: Subscription = Subscription::new();
+    let cache = board.keys.emergency;
+    subscription.location = 0; // Done as a special case
+    read_metadata(&mut subscription, cache);
+    if subscription.channel != 0 {
+        return None;
+    }
+    Some(subscription)
+}
+
+/// Helps find a subscription in flash
+/// @param keys The device keys
+/// @param channel The channel ID
+/// @return The location of the channel in the actual channel list in flash
+pub fn get_decrypt_loc_for_channel(keys: &DeviceKeys, channel: u32) -> u32 {
+    keys.channels.iter().position(|c| *c == channel).unwrap_or(0) as u32
+}
+
+/// Selects the right channel from the subscription list
+/// A channel that already has a slot keeps it, even if a slot before it was freed by unsubscribing
+/// @param channel: The channel ID.
+/// @param subscriptions: The mutable list of subscriptions.
+/// @return Gives the right position.
+pub fn get_subscription_for_channel(channel: u32, subscriptions: &mut [Option<Subscription>; 9]) -> Option<u32> {
+    subscriptions.iter().position(|sub| sub.is_some_and(|sub| sub.channel == channel))
+        .or_else(|| subscriptions.iter().position(|sub| sub.is_none()))
+        .map(|i| i as u32)
+}
+
+/// Removes the subscription for a channel, logging the removal so it doesn't come back after a reset
+/// The replay state of the channel is kept, so subscribing again doesn't reopen old timestamps
+/// @param board The board, holding the flash system
+/// @param subscriptions The mutable list of subscriptions
+/// @param channel The channel ID
+/// @return Either nothing, or why the subscription couldn't be removed
+pub fn remove_subscription<C, F: Flash, R: Rng, D: Delay>(board: &mut Board<C, F, R, D>, subscriptions: &mut [Option<Subscription>; 9],
+    channel: u32) -> Result<(), DecoderError> {
+    if channel == 0 {
+        return Err(DecoderError::EmergencySubscription);
+    }
+    // Slot 0 is the emergency subscription, which lives in the firmware rather than the flash
+    let slot = (1..subscriptions.len()).find(|&i| subscriptions[i].is_some_and(|sub| sub.channel == channel))
+        .ok_or(DecoderError::NotSubscribed)?;
+
+    check_integrity(board)?;
+    let mut log = SubscriptionLog::load(&mut board.flash, board.keys.storage_key());
+    log.remove(&mut board.flash, channel)?;
+    subscriptions[slot] = None;
+    log.relocate(subscriptions);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoder_ids_parse_in_hex_or_decimal_or_not_at_all() {
+        assert_eq!(parse_decoder_id("0xdeadbeef"), Some(0xdead_beef));
+        assert_eq!(parse_decoder_id("0XDEADBEEF"), Some(0xdead_beef));
+        assert_eq!(parse_decoder_id("4294967295"), Some(u32::MAX));
+        assert_eq!(parse_decoder_id("0"), Some(0));
+        for bad in ["", "0x", "0x1_0000_0000", "0x100000000", "4294967296", "12ab", "-1", " 1", "0xdeadbeeg"] {
+            assert_eq!(parse_decoder_id(bad), None, "{bad}");
+        }
+    }
+}
@@ -0,0 +1,627 @@
+//! The `%`-framed message format spoken between the TV and the decoder.
+//! A message is a 4-byte header (`%`, an opcode and a little-endian u16 length) followed by its body,
+//! which travels in blocks of up to 256 bytes. The receiver sends an ACK after the header and after every block,
+//! except for DEBUG and ACK messages, which are never acknowledged.
+//! A receiver that loses track of where messages start scans ahead for the next valid header.
+//! Once the host turns on LINK_CRC, every block it sends is followed by its CRC-32, and a block that doesn't match
+//! is answered with a NAK instead of an ACK so the host sends it again.
+//! A host opens with HELLO, naming the version of this protocol it speaks, so tools and firmware that don't match
+//! find out before anything else is sent.
+//! Nothing here touches hardware, so the firmware and host tools share the same encoding.
+
+use alloc::vec::Vec;
+use core::cmp::min;
+
+/// The version of this protocol, bumped whenever a message changes or is added
+pub const PROTOCOL_VERSION: u16 = 9;
+/// The oldest version of this protocol the decoder still speaks. Version 9 added the corrupt subscriptions to INFO.
+pub const MIN_PROTOCOL_VERSION: u16 = 9;
+/// The byte every message starts with
+pub const MAGIC: u8 = b'%';
+/// The size of a message header
+pub const HEADER_SIZE: usize = 4;
+/// The largest block a message body is split into
+pub const BLOCK_SIZE: usize = 256;
+/// The ACK message, which has an empty body
+pub const ACK: [u8; HEADER_SIZE] = [MAGIC, b'A', 0, 0];
+/// The NAK message, which asks for the last block again and has an empty body
+pub const NAK: [u8; HEADER_SIZE] = [MAGIC, b'N', 0, 0];
+/// The size of the CRC-32 that follows each block when LINK_CRC is on
+pub const CRC_SIZE: usize = 4;
+/// How many times in a row a block may be NAKed before the receiver gives up on the message
+pub const MAX_BLOCK_RETRIES: usize = 3;
+
+/// The kinds of message, named by the byte that follows the magic
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Opcode {
+    Decode = b'D',
+    BatchDecode = b'B',
+    Subscribe = b'S',
+    Unsubscribe = b'U',
+    List = b'L',
+    Stream = b'T',
+    Info = b'I',
+    Options = b'O',
+    Hello = b'H',
+    Ping = b'P',
+    Ack = b'A',
+    Nak = b'N',
+    Debug = b'G',
+    Error = b'E',
+}
+
+impl Opcode {
+    /// Gets the opcode for a byte
+    /// @param byte The byte after the magic
+    /// @return The opcode, or nothing if the byte isn't one
+    pub fn from_byte(byte: u8) -> Option<Opcode> {
+        match byte {
+            b'D' => Some(Opcode::Decode),
+            b'B' => Some(Opcode::BatchDecode),
+            b'S' => Some(Opcode::Subscribe),
+            b'U' => Some(Opcode::Unsubscribe),
+            b'L' => Some(Opcode::List),
+            b'T' => Some(Opcode::Stream),
+            b'I' => Some(Opcode::Info),
+            b'O' => Some(Opcode::Options),
+            b'H' => Some(Opcode::Hello),
+            b'P' => Some(Opcode::Ping),
+            b'A' => Some(Opcode::Ack),
+            b'N' => Some(Opcode::Nak),
+            b'G' => Some(Opcode::Debug),
+            b'E' => Some(Opcode::Error),
+            _ => None,
+        }
+    }
+
+    /// Gets the byte sent for this opcode
+    /// @return The opcode byte
+    pub fn as_byte(self) -> u8 {
+        self as u8
+    }
+
+    /// Checks whether the receiver has to ACK the header and blocks of this kind of message
+    /// @return Whether ACKs are sent
+    pub fn is_acked(self) -> bool {
+        !matches!(self, Opcode::Debug | Opcode::Ack | Opcode::Nak)
+    }
+}
+
+/// Why a header couldn't be decoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The first byte wasn't `%`
+    BadMagic(u8),
+    /// The second byte isn't a known opcode
+    UnknownOpcode(u8),
+}
+
+/// The header in front of every message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessageHeader {
+    pub opcode: Opcode,
+    pub length: u16,
+}
+
+impl MessageHeader {
+    pub fn new(opcode: Opcode, length: u16) -> MessageHeader {
+        MessageHeader { opcode, length }
+    }
+
+    /// Encodes the header into the bytes that go on the wire
+    /// @return The 4 header bytes
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let length = self.length.to_le_bytes();
+        [MAGIC, self.opcode.as_byte(), length[0], length[1]]
+    }
+
+    /// Decodes a header from the wire
+    /// @param bytes The 4 header bytes
+    /// @return The header, or why it isn't one
+    pub fn decode(bytes: &[u8; HEADER_SIZE]) -> Result<MessageHeader, HeaderError> {
+        if bytes[0] != MAGIC {
+            return Err(HeaderError::BadMagic(bytes[0]));
+        }
+        let opcode = Opcode::from_byte(bytes[1]).ok_or(HeaderError::UnknownOpcode(bytes[1]))?;
+        Ok(MessageHeader { opcode, length: u16::from_le_bytes([bytes[2], bytes[3]]) })
+    }
+
+    /// Gets how many blocks the body is split into
+    /// @return The number of blocks
+    pub fn blocks(&self) -> usize {
+        (self.length as usize).div_ceil(BLOCK_SIZE)
+    }
+}
+
+/// A finished block of a message body
+#[derive(Debug, PartialEq, Eq)]
+pub struct Block<'a> {
+    /// The position of the block within the body
+    pub offset: usize,
+    /// The bytes of the block, which only fall short of BLOCK_SIZE for the last one
+    pub data: &'a [u8],
+}
+
+/// Collects a message body one byte at a time, handing back each block as it completes
+/// so the receiver knows when to ACK, without ever holding more than one block.
+pub struct BlockReassembler {
+    length: usize,
+    received: usize,
+    filled: usize,
+    block: [u8; BLOCK_SIZE],
+}
+
+impl BlockReassembler {
+    /// Starts collecting the body announced by a header
+    /// @param header The header of the message
+    pub fn new(header: &MessageHeader) -> BlockReassembler {
+        BlockReassembler { length: header.length as usize, received: 0, filled: 0, block: [0; BLOCK_SIZE] }
+    }
+
+    /// Adds the next byte of the body
+    /// @param byte The byte that was received
+    /// @return The block that this byte finished, if any
+    pub fn push(&mut self, byte: u8) -> Option<Block<'_>> {
+        if self.is_complete() {
+            return None;
+        }
+        self.block[self.filled] = byte;
+        self.filled += 1;
+        self.received += 1;
+        if self.filled < BLOCK_SIZE && self.received < self.length {
+            return None;
+        }
+        let len = self.filled;
+        self.filled = 0;
+        Some(Block { offset: self.received - len, data: &self.block[..len] })
+    }
+
+    /// Checks the block that the next byte finishes against the CRC-32 sent after it
+    /// @param last The last byte of the block, which hasn't been pushed yet
+    /// @param crc The CRC-32 that was sent with the block
+    /// @return Whether the block arrived intact
+    pub fn check_crc(&self, last: u8, crc: u32) -> bool {
+        !crc32_update(crc32_update(!0, &self.block[..self.filled]), &[last]) == crc
+    }
+
+    /// Throws away the unfinished block, so it can be received again
+    pub fn discard_block(&mut self) {
+        self.received -= self.filled;
+        self.filled = 0;
+    }
+
+    /// Gets how many more bytes finish the current block
+    /// @return The bytes left in the current block, or 0 once the body is complete
+    pub fn pending(&self) -> usize {
+        min(BLOCK_SIZE - self.filled, self.length - self.received)
+    }
+
+    /// Gets how much of the body has arrived
+    /// @return The number of bytes received so far
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Checks whether the whole body has arrived
+    /// @return Whether every block is finished
+    pub fn is_complete(&self) -> bool {
+        self.received == self.length
+    }
+}
+
+/// Computes the CRC-32 of some bytes, as zlib and Ethernet do
+/// @param bytes The bytes to check
+/// @return The CRC-32
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !crc32_update(!0, bytes)
+}
+
+/// Adds bytes to a running CRC-32, a bit at a time so no lookup table takes up flash
+/// @param crc The CRC so far, before the final inversion
+/// @param bytes The bytes to add
+/// @return The updated CRC
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// The OPTIONS flag that turns on a CRC-32 after every block the host sends
+pub const LINK_CRC: u8 = 0x01;
+/// Every OPTIONS flag the decoder knows. The body of an OPTIONS message is a single byte of flags,
+/// and the decoder answers with the ones it turned on.
+pub const LINK_FLAGS: u8 = LINK_CRC;
+
+/// Finds the next header in the bytes from the link, skipping anything that can't start one,
+/// so a dropped or garbled byte costs a single message instead of the rest of the session.
+#[derive(Default)]
+pub struct HeaderScanner {
+    bytes: [u8; HEADER_SIZE],
+    filled: usize,
+    skipped: usize,
+}
+
+impl HeaderScanner {
+    pub fn new() -> HeaderScanner {
+        HeaderScanner::default()
+    }
+
+    /// Adds the next byte from the link
+    /// @param byte The byte that was received
+    /// @return The header that this byte finished, if any
+    pub fn push(&mut self, byte: u8) -> Option<MessageHeader> {
+        match self.filled {
+            0 if byte != MAGIC => {
+                self.skipped += 1;
+                return None;
+            }
+            // A magic byte followed by another one might still start a header, so only the first is dropped
+            1 if Opcode::from_byte(byte).is_none() => {
+                if byte == MAGIC {
+                    self.skipped += 1;
+                } else {
+                    self.skipped += 2;
+                    self.filled = 0;
+                }
+                return None;
+            }
+            _ => {}
+        }
+        self.bytes[self.filled] = byte;
+        self.filled += 1;
+        if self.filled < HEADER_SIZE {
+            return None;
+        }
+        self.filled = 0;
+        MessageHeader::decode(&self.bytes).ok()
+    }
+
+    /// Drops a partial header, such as one whose remaining bytes never arrived
+    pub fn reset(&mut self) {
+        self.skipped += self.filled;
+        self.filled = 0;
+    }
+
+    /// Checks whether a header has been started
+    /// @return Whether no bytes of the next header have arrived yet
+    pub fn is_idle(&self) -> bool {
+        self.filled == 0
+    }
+
+    /// Gets how many bytes were thrown away while looking for a header
+    /// @return The number of bytes skipped
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+/// How messages are exchanged on the link
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LinkMode {
+    /// Every header and block is ACKed before the next one is sent
+    #[default]
+    LockStep,
+    /// Nothing is ACKed. The host may have as many DECODE messages waiting for an answer as the decoder granted
+    /// credits, and gets one credit back with each DECODE or ERROR answer. Only DECODE and STREAM are accepted.
+    Streaming,
+}
+
+/// The largest streaming window the decoder grants
+pub const MAX_STREAM_WINDOW: u16 = 32;
+/// The size of a STREAM request and response: the mode (0 for lock-step, 1 for streaming),
+/// then the little-endian window the host asks for or the decoder grants
+pub const STREAM_SIZE: usize = 3;
+
+/// Encodes the body of a STREAM message
+/// @param mode The mode to switch to
+/// @param window The window asked for or granted, which is 0 for lock-step
+/// @return The body of the message
+pub fn encode_stream(mode: LinkMode, window: u16) -> [u8; STREAM_SIZE] {
+    let window = window.to_le_bytes();
+    [(mode == LinkMode::Streaming) as u8, window[0], window[1]]
+}
+
+/// Decodes the body of a STREAM message
+/// @param bytes The body of the message
+/// @return The mode and window, or nothing if the body is malformed
+pub fn decode_stream(bytes: &[u8]) -> Option<(LinkMode, u16)> {
+    let mode = match bytes.first()? {
+        0 => LinkMode::LockStep,
+        1 => LinkMode::Streaming,
+        _ => return None,
+    };
+    let window: [u8; 2] = bytes.get(1..STREAM_SIZE)?.try_into().ok()?;
+    (bytes.len() == STREAM_SIZE).then_some((mode, u16::from_le_bytes(window)))
+}
+
+/// The most frame packets a BATCH_DECODE message may carry
+pub const MAX_BATCH: usize = 16;
+/// The status of a BATCH_DECODE result whose frame was decoded. Any other status is the code of the DecoderError
+/// the frame was rejected with.
+pub const STATUS_OK: u16 = 0;
+
+/// Encodes one result of a BATCH_DECODE response: a little-endian status and frame length, followed by the decoded frame.
+/// A rejected frame is empty, and the results follow each other with nothing in between.
+/// @param status The status of the frame
+/// @param frame The decoded frame
+/// @param out The response the result is added to
+pub fn encode_batch_result(status: u16, frame: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&status.to_le_bytes());
+    out.extend_from_slice(&(frame.len() as u16).to_le_bytes());
+    out.extend_from_slice(frame);
+}
+
+/// The size of the status and frame length in front of each BATCH_DECODE result
+pub const BATCH_RESULT_OVERHEAD: usize = 4;
+/// The size of the largest BATCH_DECODE response, with every frame decoded at MAX_FRAME_SIZE
+pub const MAX_BATCH_RESPONSE: usize = MAX_BATCH * (BATCH_RESULT_OVERHEAD + crate::MAX_FRAME_SIZE);
+
+/// The size of an encoded InfoRecord
+pub const INFO_SIZE: usize = 100;
+
+/// The body of an INFO response, describing the decoder and what it can do.
+/// Every field is little-endian and at a fixed offset, in the order they are declared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InfoRecord {
+    pub protocol_version: u16,
+    pub decoder_id: u32,
+    /// The major, minor and patch version of the firmware
+    pub firmware_version: [u8; 3],
+    /// The commit the firmware was built from, or all zeroes if it isn't known
+    pub git_hash: [u8; 20],
+    /// How many subscriptions can be stored, not counting the emergency channel
+    pub slots: u8,
+    /// How many of those are unused
+    pub free_slots: u8,
+    pub max_frame_size: u16,
+    /// How many entries of `channels` are used
+    pub channel_count: u8,
+    /// The channels the decoder was built for, not counting the emergency channel
+    pub channels: [u32; 16],
+    /// One bit per entry of `channels`, set if the subscription stored for it failed its MAC and was dropped
+    pub corrupt: u16,
+}
+
+impl InfoRecord {
+    /// Encodes the record into an INFO response body
+    /// @return The encoded record
+    pub fn encode(&self) -> [u8; INFO_SIZE] {
+        let mut ret = [0u8; INFO_SIZE];
+        ret[0..2].copy_from_slice(&self.protocol_version.to_le_bytes());
+        ret[2..6].copy_from_slice(&self.decoder_id.to_le_bytes());
+        ret[6..9].copy_from_slice(&self.firmware_version);
+        ret[9..29].copy_from_slice(&self.git_hash);
+        ret[29] = self.slots;
+        ret[30] = self.free_slots;
+        ret[31..33].copy_from_slice(&self.max_frame_size.to_le_bytes());
+        ret[33] = self.channel_count;
+        for (i, channel) in self.channels.iter().enumerate() {
+            ret[34 + i * 4..38 + i * 4].copy_from_slice(&channel.to_le_bytes());
+        }
+        ret[98..100].copy_from_slice(&self.corrupt.to_le_bytes());
+        ret
+    }
+
+    /// Decodes a record from an INFO response body
+    /// @param bytes The body of the response
+    /// @return The record, or nothing if the body is the wrong size
+    pub fn decode(bytes: &[u8]) -> Option<InfoRecord> {
+        let bytes: &[u8; INFO_SIZE] = bytes.try_into().ok()?;
+        let mut channels = [0u32; 16];
+        for (i, channel) in channels.iter_mut().enumerate() {
+            *channel = u32::from_le_bytes(bytes[34 + i * 4..38 + i * 4].try_into().unwrap());
+        }
+        Some(InfoRecord {
+            protocol_version: u16::from_le_bytes([bytes[0], bytes[1]]),
+            decoder_id: u32::from_le_bytes(bytes[2..6].try_into().unwrap()),
+            firmware_version: bytes[6..9].try_into().unwrap(),
+            git_hash: bytes[9..29].try_into().unwrap(),
+            slots: bytes[29],
+            free_slots: bytes[30],
+            max_frame_size: u16::from_le_bytes([bytes[31], bytes[32]]),
+            channel_count: bytes[33],
+            channels,
+            corrupt: u16::from_le_bytes([bytes[98], bytes[99]]),
+        })
+    }
+}
+
+/// The size of a HELLO request: the little-endian protocol version the host speaks
+pub const HELLO_REQUEST_SIZE: usize = 2;
+/// The size of an encoded HelloRecord
+pub const HELLO_SIZE: usize = 8;
+
+/// The HelloRecord feature bit for BATCH_DECODE
+pub const FEATURE_BATCH: u32 = 0x01;
+/// The HelloRecord feature bit for LINK_CRC
+pub const FEATURE_CRC: u32 = 0x02;
+/// The HelloRecord feature bit for streaming mode
+pub const FEATURE_STREAM: u32 = 0x04;
+
+/// The body of a HELLO response, telling the host which versions of this protocol the decoder speaks
+/// and which optional features it has. Every field is little-endian, in the order they are declared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HelloRecord {
+    pub min_version: u16,
+    pub max_version: u16,
+    /// The FEATURE bits that are set
+    pub features: u32,
+}
+
+impl HelloRecord {
+    /// Checks whether the decoder speaks a version of this protocol
+    /// @param version The version the host speaks
+    /// @return Whether the version is in range
+    pub fn supports(&self, version: u16) -> bool {
+        (self.min_version..=self.max_version).contains(&version)
+    }
+
+    /// Encodes the record into a HELLO response body
+    /// @return The encoded record
+    pub fn encode(&self) -> [u8; HELLO_SIZE] {
+        let mut ret = [0u8; HELLO_SIZE];
+        ret[0..2].copy_from_slice(&self.min_version.to_le_bytes());
+        ret[2..4].copy_from_slice(&self.max_version.to_le_bytes());
+        ret[4..8].copy_from_slice(&self.features.to_le_bytes());
+        ret
+    }
+
+    /// Decodes a record from a HELLO response body
+    /// @param bytes The body of the response
+    /// @return The record, or nothing if the body is the wrong size
+    pub fn decode(bytes: &[u8]) -> Option<HelloRecord> {
+        let bytes: &[u8; HELLO_SIZE] = bytes.try_into().ok()?;
+        Some(HelloRecord {
+            min_version: u16::from_le_bytes([bytes[0], bytes[1]]),
+            max_version: u16::from_le_bytes([bytes[2], bytes[3]]),
+            features: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        })
+    }
+}
+
+/// The largest payload a PING message may carry
+pub const MAX_PING: usize = BLOCK_SIZE;
+/// The size of an encoded LinkStats
+pub const STATS_SIZE: usize = 12;
+
+/// What the decoder has seen on the link since it booted, sent ahead of the payload in a PING response.
+/// Every field is little-endian, in the order they are declared, and wraps around once it overflows.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// How many bytes of the host's messages arrived, including ones skipped while looking for a header but not its ACKs
+    pub received: u32,
+    /// How many headers or bodies stopped short, failed their checks or were too long
+    pub framing_errors: u32,
+    /// How many headers were found only after skipping bytes that couldn't start one
+    pub resyncs: u32,
+}
+
+impl LinkStats {
+    /// Encodes the counters for a PING response
+    /// @return The encoded counters
+    pub fn encode(&self) -> [u8; STATS_SIZE] {
+        let mut ret = [0u8; STATS_SIZE];
+        ret[0..4].copy_from_slice(&self.received.to_le_bytes());
+        ret[4..8].copy_from_slice(&self.framing_errors.to_le_bytes());
+        ret[8..12].copy_from_slice(&self.resyncs.to_le_bytes());
+        ret
+    }
+
+    /// Decodes the counters at the start of a PING response
+    /// @param bytes The body of the response
+    /// @return The counters, or nothing if the body is too short to hold them
+    pub fn decode(bytes: &[u8]) -> Option<LinkStats> {
+        let bytes: &[u8; STATS_SIZE] = bytes.get(..STATS_SIZE)?.try_into().ok()?;
+        Some(LinkStats {
+            received: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            framing_errors: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            resyncs: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    const OPCODES: [Opcode; 14] = [Opcode::Decode, Opcode::BatchDecode, Opcode::Subscribe, Opcode::Unsubscribe, Opcode::List,
+        Opcode::Stream, Opcode::Info, Opcode::Options, Opcode::Hello, Opcode::Ping, Opcode::Ack, Opcode::Nak, Opcode::Debug,
+        Opcode::Error];
+
+    /// Pushes a whole body through a reassembler
+    /// @return Every block it handed back, as its offset and bytes
+    fn reassemble(body: &[u8]) -> Vec<(usize, Vec<u8>)> {
+        let mut reassembler = BlockReassembler::new(&MessageHeader::new(Opcode::Subscribe, body.len() as u16));
+        let blocks = body.iter().filter_map(|&byte| reassembler.push(byte).map(|block| (block.offset, block.data.to_vec()))).collect();
+        assert!(reassembler.is_complete());
+        assert_eq!(reassembler.received(), body.len());
+        blocks
+    }
+
+    #[test]
+    fn header_round_trip() {
+        for opcode in OPCODES {
+            assert_eq!(Opcode::from_byte(opcode.as_byte()), Some(opcode));
+            for length in [0, 1, BLOCK_SIZE as u16, 0x1234, u16::MAX] {
+                let header = MessageHeader::new(opcode, length);
+                assert_eq!(MessageHeader::decode(&header.encode()), Ok(header));
+            }
+        }
+        // Laid out as the Python host packs it, MAGIC + struct.pack("<BH", opcode, length)
+        assert_eq!(MessageHeader::new(Opcode::Subscribe, 0x1234).encode(), [b'%', b'S', 0x34, 0x12]);
+        assert_eq!(MessageHeader::decode(&[b'#', b'S', 0, 0]), Err(HeaderError::BadMagic(b'#')));
+        assert_eq!(MessageHeader::decode(&[b'%', b'Z', 0, 0]), Err(HeaderError::UnknownOpcode(b'Z')));
+    }
+
+    #[test]
+    fn reassembled_blocks_make_up_the_body() {
+        let body: Vec<u8> = (0..700u32).map(|i| (i * 7) as u8).collect();
+        let blocks = reassemble(&body);
+        assert_eq!(blocks.iter().map(|(offset, _)| *offset).collect::<Vec<_>>(), [0, BLOCK_SIZE, 2 * BLOCK_SIZE]);
+        assert_eq!(blocks.into_iter().flat_map(|(_, data)| data).collect::<Vec<u8>>(), body);
+    }
+
+    #[test]
+    fn exact_multiple_ends_on_a_full_block() {
+        let body = [0x5A; 2 * BLOCK_SIZE];
+        let blocks = reassemble(&body);
+        assert_eq!(blocks.len(), MessageHeader::new(Opcode::Decode, body.len() as u16).blocks());
+        assert!(blocks.iter().all(|(_, data)| data.len() == BLOCK_SIZE));
+    }
+
+    #[test]
+    fn partial_last_block_is_handed_back_short() {
+        let mut reassembler = BlockReassembler::new(&MessageHeader::new(Opcode::Decode, (BLOCK_SIZE + 44) as u16));
+        for _ in 0..BLOCK_SIZE {
+            reassembler.push(1);
+        }
+        assert_eq!(reassembler.pending(), 44);
+        for _ in 0..43 {
+            assert_eq!(reassembler.push(2), None);
+        }
+        assert_eq!(reassembler.push(2), Some(Block { offset: BLOCK_SIZE, data: &[2; 44] }));
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn bytes_past_the_length_are_ignored() {
+        let mut reassembler = BlockReassembler::new(&MessageHeader::new(Opcode::Decode, 3));
+        assert_eq!(reassembler.push(1), None);
+        assert_eq!(reassembler.push(2), None);
+        assert_eq!(reassembler.push(3), Some(Block { offset: 0, data: &[1, 2, 3] }));
+        assert_eq!(reassembler.push(4), None);
+        assert_eq!(reassembler.received(), 3);
+    }
+
+    #[test]
+    fn crc_matches_the_python_host() {
+        // zlib.crc32 of each input, and its struct.pack("<I", ...) trailer
+        let vectors: [(&[u8], u32, [u8; CRC_SIZE]); 3] = [
+            (b"", 0, [0, 0, 0, 0]),
+            (b"123456789", 0xCBF4_3926, [38, 57, 244, 203]),
+            (b"%S\x10\x00", 0x9290_02A8, [168, 2, 144, 146]),
+        ];
+        for (bytes, crc, trailer) in vectors {
+            assert_eq!(crc32(bytes), crc);
+            assert_eq!(crc.to_le_bytes(), trailer);
+        }
+
+        // A block followed by the trailer the host sends with it
+        let block: Vec<u8> = (0..=255).collect();
+        let trailer = u32::from_le_bytes([115, 140, 5, 41]);
+        assert_eq!(crc32(&block), trailer);
+        let mut reassembler = BlockReassembler::new(&MessageHeader::new(Opcode::Subscribe, BLOCK_SIZE as u16));
+        for &byte in &block[..BLOCK_SIZE - 1] {
+            assert_eq!(reassembler.push(byte), None);
+        }
+        assert!(reassembler.check_crc(255, trailer));
+        assert!(!reassembler.check_crc(254, trailer));
+        assert!(!reassembler.check_crc(255, trailer ^ 1));
+    }
+}
@@ -0,0 +1,173 @@
+use crate::error::DecoderError;
+use crate::hw::Flash;
+use crate::replay_log::ReplayLog;
+use crate::subscription::Subscription;
+
+/// Decides which earlier frames a new frame's timestamp is compared against
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayPolicy {
+    /// Timestamps must strictly increase across every frame the decoder accepts, whatever the channel
+    StrictGlobal,
+    /// Timestamps must only strictly increase within each channel
+    StrictPerChannel,
+}
+
+/// Tracks the last accepted timestamp of the whole decoder, alongside each subscription's own state
+/// Each channel's latest accepted timestamp is persisted before its frame goes out, so that power-cycling the board
+/// doesn't reopen old frames
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayGuard {
+    policy: ReplayPolicy,
+    last_frame: Option<u64>,
+    log: ReplayLog,
+}
+
+impl ReplayGuard {
+    /// Restores the replay state persisted before the last reset
+    /// @param policy The replay policy to enforce
+    /// @param flash The flash holding the replay log
+    /// @return The restored replay state
+    pub fn load<F: Flash>(policy: ReplayPolicy, flash: &mut F) -> ReplayGuard {
+        let log = ReplayLog::load(flash);
+        ReplayGuard {
+            policy,
+            last_frame: log.latest(),
+            log,
+        }
+    }
+
+    /// Gives a freshly loaded subscription the replay state persisted for its channel
+    /// @param sub The subscription to restore
+    pub fn restore(&self, sub: &mut Subscription) {
+        sub.last_frame = self.log.last_frame(sub.channel);
+    }
+
+    /// Checks whether a frame may be accepted under the configured policy
+    /// @param sub The subscription the frame is decoded with
+    /// @param timestamp The timestamp of the frame
+    /// @return Whether the frame is newer than everything it must be compared against
+    pub fn is_fresh(&self, sub: &Subscription, timestamp: u64) -> bool {
+        if !sub.is_fresh(timestamp) {
+            return false;
+        }
+        match (self.policy, self.last_frame) {
+            (ReplayPolicy::StrictGlobal, Some(last)) => timestamp > last,
+            _ => true,
+        }
+    }
+
+    /// Records an accepted frame for its channel and for the whole decoder, leaving it to the next flush to persist
+    /// @param sub The live subscription the frame was decoded with
+    /// @param timestamp The timestamp of the frame
+    /// @return Either nothing, or the error that kept the log from taking the frame, which then isn't accepted
+    pub fn accept(&mut self, sub: &mut Subscription, timestamp: u64) -> Result<(), DecoderError> {
+        self.log.note(sub.channel, timestamp)?;
+        sub.accept(timestamp);
+        if self.last_frame.is_none_or(|last| timestamp > last) {
+            self.last_frame = Some(timestamp);
+        }
+        Ok(())
+    }
+
+    /// Persists every frame accepted since the last flush, which has to happen before any of them goes out
+    /// @param flash The flash holding the replay log
+    /// @return Either nothing, or the error that stopped the timestamps from being persisted
+    pub fn flush<F: Flash>(&mut self, flash: &mut F) -> Result<(), DecoderError> {
+        self.log.flush(flash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::decode_subroutine;
+    use crate::fixtures::{board, install, Secrets, TestBoard};
+    use crate::load_subscriptions;
+
+    const FRAME: [u8; 64] = [7; 64];
+
+    /// A decoder subscribed to channel 1, booted the way the firmware boots after a reset
+    struct Decoder {
+        board: TestBoard,
+        subscriptions: [Option<Subscription>; 9],
+        replay: ReplayGuard,
+    }
+
+    impl Decoder {
+        fn boot(secrets: &Secrets, policy: ReplayPolicy) -> Decoder {
+            let mut board = board(secrets);
+            let mut subscriptions = load_subscriptions(&mut board);
+            let replay = ReplayGuard::load(policy, &mut board.flash);
+            install(&mut board, &mut subscriptions, &replay, &secrets.subscription(1, 100, 5000));
+            Decoder { board, subscriptions, replay }
+        }
+
+        /// Boots again from the same flash, the way the firmware does after a power cycle
+        fn reset(self, policy: ReplayPolicy) -> Decoder {
+            let mut board = self.board;
+            let mut subscriptions = load_subscriptions(&mut board);
+            let replay = ReplayGuard::load(policy, &mut board.flash);
+            for sub in subscriptions.iter_mut().flatten() {
+                replay.restore(sub);
+            }
+            Decoder { board, subscriptions, replay }
+        }
+
+        /// Decodes a frame, answering the way the DECODE command would
+        fn decode(&mut self, secrets: &Secrets, channel: u32, timestamp: u64) -> Result<(), DecoderError> {
+            let packet = secrets.encode(channel, &FRAME, timestamp);
+            let frame = decode_subroutine(&mut self.board, &mut self.subscriptions, &mut self.replay, secrets.verifier(), &packet)?;
+            assert_eq!(frame, FRAME);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn old_frames_are_rejected_on_a_channel() {
+        let secrets = Secrets::new(&[1]);
+        let mut decoder = Decoder::boot(&secrets, ReplayPolicy::StrictPerChannel);
+        assert_eq!(decoder.decode(&secrets, 1, 200), Ok(()));
+        assert_eq!(decoder.decode(&secrets, 1, 200), Err(DecoderError::Replayed));
+        assert_eq!(decoder.decode(&secrets, 1, 150), Err(DecoderError::Replayed));
+        assert_eq!(decoder.decode(&secrets, 1, 201), Ok(()));
+    }
+
+    #[test]
+    fn old_frames_are_rejected_on_the_emergency_channel() {
+        let secrets = Secrets::new(&[1]);
+        let mut decoder = Decoder::boot(&secrets, ReplayPolicy::StrictPerChannel);
+        assert_eq!(decoder.decode(&secrets, 0, 300), Ok(()));
+        assert_eq!(decoder.decode(&secrets, 0, 300), Err(DecoderError::Replayed));
+        assert_eq!(decoder.decode(&secrets, 0, 299), Err(DecoderError::Replayed));
+        assert_eq!(decoder.decode(&secrets, 0, 301), Ok(()));
+        // The other channels only answer to their own frames under this policy
+        assert_eq!(decoder.decode(&secrets, 1, 200), Ok(()));
+    }
+
+    #[test]
+    fn old_frames_stay_rejected_after_a_reset() {
+        let secrets = Secrets::new(&[1]);
+        let mut decoder = Decoder::boot(&secrets, ReplayPolicy::StrictPerChannel);
+        assert_eq!(decoder.decode(&secrets, 1, 200), Ok(()));
+        assert_eq!(decoder.decode(&secrets, 0, 50), Ok(()));
+
+        let mut decoder = decoder.reset(ReplayPolicy::StrictPerChannel);
+        assert_eq!(decoder.decode(&secrets, 1, 200), Err(DecoderError::Replayed));
+        assert_eq!(decoder.decode(&secrets, 0, 50), Err(DecoderError::Replayed));
+        // Only what was accepted is persisted, so the very next frames still go through
+        assert_eq!(decoder.decode(&secrets, 1, 201), Ok(()));
+        assert_eq!(decoder.decode(&secrets, 0, 51), Ok(()));
+    }
+
+    #[test]
+    fn global_policy_compares_every_channel() {
+        let secrets = Secrets::new(&[1]);
+        let mut decoder = Decoder::boot(&secrets, ReplayPolicy::StrictGlobal);
+        assert_eq!(decoder.decode(&secrets, 1, 300), Ok(()));
+        assert_eq!(decoder.decode(&secrets, 0, 300), Err(DecoderError::Replayed));
+        assert_eq!(decoder.decode(&secrets, 0, 250), Err(DecoderError::Replayed));
+        assert_eq!(decoder.decode(&secrets, 0, 301), Ok(()));
+        assert_eq!(decoder.decode(&secrets, 1, 301), Err(DecoderError::Replayed));
+        assert_eq!(decoder.decode(&secrets, 1, 302), Ok(()));
+    }
+}
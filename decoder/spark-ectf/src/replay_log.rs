@@ -0,0 +1,377 @@
+use blake3::Hasher;
+use crate::error::DecoderError;
+use crate::hw::Flash;
+use crate::subscription_log::LOG_CHANNELS;
+use crate::{SUB_LOC, SUB_SPACE};
+
+/// Pages the log rotates through, so that no single page takes every erase
+pub const REPLAY_PAGES: u32 = 2;
+/// The location of the replay log, directly below the subscription pages
+pub const REPLAY_LOC: u32 = SUB_LOC - REPLAY_PAGES * SUB_SPACE;
+/// The most channels the log keeps a timestamp for: every channel the subscription log can hold, and the emergency channel
+pub const REPLAY_CHANNELS: usize = LOG_CHANNELS + 1;
+
+/// Every record is one 128-bit flash word: tag, timestamp (high, low) and check
+const RECORD_SIZE: u32 = 16;
+const RECORDS_PER_PAGE: u32 = SUB_SPACE / RECORD_SIZE;
+/// Tag of the header record in the first slot of every formatted page
+const HEADER_MAGIC: u32 = 0x5350_524b;
+const ERASED: [u32; 4] = [0xFFFF_FFFF; 4];
+
+/// A power-loss-safe log of the latest accepted timestamp of each channel, which no frame at or before is accepted after a reset
+/// Accepted timestamps are noted in memory and persisted by a flush, which writes one record for each channel that moved on,
+/// so a BATCH_DECODE costs one record per channel rather than one per frame.
+/// Records are appended to the active page. Once it fills up, the timestamp of each channel is copied to the next page,
+/// which only takes over once its header (written last) is valid, so each page is erased about once every
+/// REPLAY_PAGES * RECORDS_PER_PAGE records.
+/// Records carry a check value, so a write torn by a reset is ignored on the next boot.
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayLog {
+    channels: [u32; REPLAY_CHANNELS],
+    /// The latest frame accepted on each channel
+    timestamps: [u64; REPLAY_CHANNELS],
+    /// Which channels accepted a frame that the log doesn't hold yet
+    dirty: [bool; REPLAY_CHANNELS],
+    count: usize,
+    page: u32,
+    generation: u32,
+    next: u32,
+}
+
+impl ReplayLog {
+    /// Restores the log from flash, formatting it if no page holds a valid header
+    /// @param flash The flash holding the log
+    /// @return The restored log
+    pub fn load<F: Flash>(flash: &mut F) -> ReplayLog {
+        let mut log = ReplayLog {
+            channels: [0; REPLAY_CHANNELS],
+            timestamps: [0; REPLAY_CHANNELS],
+            dirty: [false; REPLAY_CHANNELS],
+            count: 0,
+            page: 0,
+            generation: 0,
+            next: RECORDS_PER_PAGE,
+        };
+
+        // Picks the newest page with an intact header
+        let mut found = false;
+        for page in 0..REPLAY_PAGES {
+            let header = read_record(flash, page_address(page));
+            match decode_record(header) {
+                Some((HEADER_MAGIC, generation)) if !found || generation > log.generation as u64 => {
+                    log.page = page;
+                    log.generation = generation as u32;
+                    found = true;
+                }
+                _ => {}
+            }
+        }
+        if !found {
+            let _ = log.format(flash, 0, 1);
+            return log;
+        }
+
+        // Replays every intact record, skipping torn ones, up to the first erased slot
+        for slot in 1..RECORDS_PER_PAGE {
+            let record = read_record(flash, page_address(log.page) + slot * RECORD_SIZE);
+            if record == ERASED {
+                log.next = slot;
+                break;
+            }
+            if let Some((channel, timestamp)) = decode_record(record) {
+                let _ = log.remember(channel, timestamp);
+            }
+        }
+        log
+    }
+
+    /// Gets the persisted timestamp of a channel, which is what a reset leaves as its last accepted frame
+    /// @param channel The channel ID
+    /// @return The timestamp, or None if the channel never accepted a frame
+    pub fn last_frame(&self, channel: u32) -> Option<u64> {
+        self.position(channel).map(|i| self.timestamps[i])
+    }
+
+    /// Gets the highest timestamp over all channels
+    /// @return The timestamp, or None if no frame was ever accepted
+    pub fn latest(&self) -> Option<u64> {
+        self.timestamps[..self.count].iter().copied().max()
+    }
+
+    /// Notes an accepted timestamp in memory, for the next flush to persist
+    /// @param channel The channel ID of the frame
+    /// @param timestamp The timestamp of the frame
+    /// @return Either nothing, or ReplayLogFull if the channel is new and the table has no room for it
+    pub fn note(&mut self, channel: u32, timestamp: u64) -> Result<(), DecoderError> {
+        if let Some(i) = self.remember(channel, timestamp)? {
+            self.dirty[i] = true;
+        }
+        Ok(())
+    }
+
+    /// Persists every timestamp noted since the last flush, rotating to the next page once the active one is full
+    /// @param flash The flash holding the log
+    /// @return Either nothing, or the flash error that stopped the write, leaving what wasn't written for the next flush
+    pub fn flush<F: Flash>(&mut self, flash: &mut F) -> Result<(), DecoderError> {
+        for i in 0..self.count {
+            if !self.dirty[i] {
+                continue;
+            }
+            if self.next >= RECORDS_PER_PAGE {
+                // The compacted page holds every timestamp, the ones not written yet included
+                return self.format(flash, (self.page + 1) % REPLAY_PAGES, self.generation.wrapping_add(1));
+            }
+            let address = page_address(self.page) + self.next * RECORD_SIZE;
+            if let Err(err) = flash.write_128(address, &encode_record(self.channels[i], self.timestamps[i])) {
+                // The slot may have been left erased, which would hide every record after it on the next boot,
+                // so the next flush starts over on the other page
+                self.next = RECORDS_PER_PAGE;
+                return Err(err.into());
+            }
+            self.next += 1;
+            self.dirty[i] = false;
+        }
+        Ok(())
+    }
+
+    /// Notes an accepted timestamp and persists it straight away
+    /// @param flash The flash holding the log
+    /// @param channel The channel ID of the frame
+    /// @param timestamp The timestamp of the frame
+    /// @return Either nothing, or the error that stopped the timestamp from being persisted
+    pub fn record<F: Flash>(&mut self, flash: &mut F, channel: u32, timestamp: u64) -> Result<(), DecoderError> {
+        self.note(channel, timestamp)?;
+        self.flush(flash)
+    }
+
+    /// Finds where a channel is kept in the table
+    /// @param channel The channel ID
+    /// @return The index of the channel, or None if it isn't there
+    fn position(&self, channel: u32) -> Option<usize> {
+        (0..self.count).find(|&i| self.channels[i] == channel)
+    }
+
+    /// Moves the timestamp of a channel in the in-memory table forward, adding the channel if it isn't there yet
+    /// A full table refuses a new channel rather than forgetting another one, which would reopen its old frames
+    /// @param channel The channel ID
+    /// @param timestamp The timestamp of the channel
+    /// @return The index of the channel if its timestamp changed, or ReplayLogFull if there was no room for it
+    fn remember(&mut self, channel: u32, timestamp: u64) -> Result<Option<usize>, DecoderError> {
+        match self.position(channel) {
+            Some(i) if self.timestamps[i] >= timestamp => Ok(None),
+            Some(i) => {
+                self.timestamps[i] = timestamp;
+                Ok(Some(i))
+            }
+            None if self.count < REPLAY_CHANNELS => {
+                self.channels[self.count] = channel;
+                self.timestamps[self.count] = timestamp;
+                self.count += 1;
+                Ok(Some(self.count - 1))
+            }
+            None => Err(DecoderError::ReplayLogFull),
+        }
+    }
+
+    /// Erases a page and writes the current table into it, committing it with its header
+    /// @param flash The flash holding the log
+    /// @param page The index of the page in the log
+    /// @param generation The generation the page takes over with
+    /// @return Either nothing, or the flash error that stopped the compaction
+    fn format<F: Flash>(&mut self, flash: &mut F, page: u32, generation: u32) -> Result<(), DecoderError> {
+        let address = page_address(page);
+        flash.erase_page(address)?;
+        for i in 0..self.count {
+            flash.write_128(address + (i as u32 + 1) * RECORD_SIZE, &encode_record(self.channels[i], self.timestamps[i]))?;
+        }
+        // Until the header is written, the previous page stays the valid one
+        flash.write_128(address, &encode_record(HEADER_MAGIC, generation as u64))?;
+        self.page = page;
+        self.generation = generation;
+        self.next = self.count as u32 + 1;
+        self.dirty = [false; REPLAY_CHANNELS];
+        Ok(())
+    }
+}
+
+/// Gets the address of one page of the log
+/// @param page The index of the page in the log
+/// @return The address of the page in flash
+fn page_address(page: u32) -> u32 {
+    REPLAY_LOC + page * SUB_SPACE
+}
+
+/// Reads one record from flash, treating unreadable words as erased
+/// @param flash The flash holding the log
+/// @param address The 128-bit aligned address of the record
+/// @return The raw record
+fn read_record<F: Flash>(flash: &F, address: u32) -> [u32; 4] {
+    flash.read_128(address).unwrap_or(ERASED)
+}
+
+/// Packs a tag and a value into a record, along with their check value
+/// @param tag The channel ID, or the header magic
+/// @param value The timestamp, or the page generation
+/// @return The raw record
+fn encode_record(tag: u32, value: u64) -> [u32; 4] {
+    [tag, (value >> 32) as u32, value as u32, check(tag, value)]
+}
+
+/// Unpacks a record, rejecting it if it was torn or corrupted
+/// @param record The raw record
+/// @return The tag and value of the record, if it is intact
+fn decode_record(record: [u32; 4]) -> Option<(u32, u64)> {
+    let value = ((record[1] as u64) << 32) | record[2] as u64;
+    if record == ERASED || check(record[0], value) != record[3] {
+        return None;
+    }
+    Some((record[0], value))
+}
+
+/// Calculates the check value of a record using BLAKE3
+/// @param tag The channel ID, or the header magic
+/// @param value The timestamp, or the page generation
+/// @return The check value
+fn check(tag: u32, value: u64) -> u32 {
+    let hash = Hasher::new().update(&tag.to_be_bytes()).update(&value.to_be_bytes()).finalize();
+    u32::from_be_bytes(hash.as_bytes()[0..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::CountingFlash;
+
+    /// Gets the persisted timestamp of every channel in the table
+    fn marks(log: &ReplayLog) -> [Option<u64>; 3] {
+        [0, 1, 2].map(|channel| log.last_frame(channel))
+    }
+
+    #[test]
+    fn the_latest_timestamp_is_persisted_exactly() {
+        let mut flash = CountingFlash::new();
+        let mut log = ReplayLog::load(&mut flash);
+        let writes = flash.writes;
+        for timestamp in [1000, 1001, 1_000_000] {
+            log.record(&mut flash, 1, timestamp).unwrap();
+            assert_eq!(ReplayLog::load(&mut flash).last_frame(1), Some(timestamp));
+        }
+        assert_eq!(flash.writes - writes, 3);
+
+        // An older timestamp, which the guard would have refused anyway, changes nothing
+        log.record(&mut flash, 1, 5).unwrap();
+        assert_eq!(flash.writes - writes, 3);
+        assert_eq!(ReplayLog::load(&mut flash).last_frame(1), Some(1_000_000));
+    }
+
+    #[test]
+    fn noted_timestamps_wait_for_the_flush() {
+        let mut flash = CountingFlash::new();
+        let mut log = ReplayLog::load(&mut flash);
+        let writes = flash.writes;
+        for timestamp in 100..116 {
+            log.note(1 + timestamp as u32 % 2, timestamp).unwrap();
+        }
+        assert_eq!(marks(&log), [None, Some(114), Some(115)]);
+        assert_eq!(marks(&ReplayLog::load(&mut flash)), [None; 3]);
+
+        // One record for each channel that moved on, however many of its frames were noted
+        log.flush(&mut flash).unwrap();
+        assert_eq!(flash.writes - writes, 2);
+        assert_eq!(marks(&ReplayLog::load(&mut flash)), [None, Some(114), Some(115)]);
+        log.flush(&mut flash).unwrap();
+        assert_eq!(flash.writes - writes, 2);
+
+        // A flush the flash refused leaves its timestamps for the next one, which moves to the other page
+        log.note(1, 200).unwrap();
+        flash.budget = Some(0);
+        assert!(log.flush(&mut flash).is_err());
+        flash.budget = None;
+        log.flush(&mut flash).unwrap();
+        assert_eq!(log.generation, 2);
+        assert_eq!(marks(&ReplayLog::load(&mut flash)), [None, Some(200), Some(115)]);
+    }
+
+    #[test]
+    fn a_full_table_refuses_new_channels() {
+        let mut flash = CountingFlash::new();
+        let mut log = ReplayLog::load(&mut flash);
+        for channel in 0..REPLAY_CHANNELS as u32 {
+            log.record(&mut flash, channel, 100).unwrap();
+        }
+        let extra = REPLAY_CHANNELS as u32;
+        assert_eq!(log.record(&mut flash, extra, 100), Err(DecoderError::ReplayLogFull));
+
+        // Nothing was forgotten to make room, and the channels already there still move on
+        log.record(&mut flash, 0, 101).unwrap();
+        let restored = ReplayLog::load(&mut flash);
+        assert_eq!(restored.last_frame(extra), None);
+        assert_eq!(restored.last_frame(0), Some(101));
+        assert!((1..REPLAY_CHANNELS as u32).all(|channel| restored.last_frame(channel) == Some(100)));
+    }
+
+    #[test]
+    fn marks_survive_rotation() {
+        let mut flash = CountingFlash::new();
+        let mut log = ReplayLog::load(&mut flash);
+        let mut timestamp = 0;
+        for _ in 0..2 * RECORDS_PER_PAGE {
+            timestamp += 1;
+            log.record(&mut flash, 1, timestamp).unwrap();
+            log.record(&mut flash, 2, timestamp).unwrap();
+        }
+        log.record(&mut flash, 0, 5).unwrap();
+        assert!(log.generation >= 3, "generation {}", log.generation);
+        // Every page was erased once, the first when the fresh flash was formatted
+        assert_eq!(flash.erases, log.generation as usize);
+
+        let restored = ReplayLog::load(&mut flash);
+        assert_eq!(marks(&restored), marks(&log));
+        assert_eq!((restored.page, restored.generation, restored.next), (log.page, log.generation, log.next));
+        assert_eq!(restored.last_frame(1), Some(timestamp));
+        assert_eq!(restored.latest(), restored.last_frame(1).max(restored.last_frame(2)));
+    }
+
+    #[test]
+    fn torn_record_is_skipped() {
+        let mut flash = CountingFlash::new();
+        let mut log = ReplayLog::load(&mut flash);
+        log.record(&mut flash, 1, 100).unwrap();
+        log.record(&mut flash, 1, 200).unwrap();
+
+        // A reset in the middle of a write leaves a record whose check value doesn't match
+        let torn = page_address(log.page) + log.next * RECORD_SIZE;
+        flash.write_128(torn, &[1, 0, 900, 0xFFFF_0000]).unwrap();
+        let mut log = ReplayLog::load(&mut flash);
+        assert_eq!(log.last_frame(1), Some(200));
+        assert_eq!(page_address(log.page) + log.next * RECORD_SIZE, torn + RECORD_SIZE);
+
+        // The next record goes after it, and takes over
+        log.record(&mut flash, 1, 300).unwrap();
+        assert_eq!(ReplayLog::load(&mut flash).last_frame(1), Some(300));
+    }
+
+    #[test]
+    fn interrupted_rotation_keeps_the_old_page() {
+        for budget in 0..=4 {
+            let mut flash = CountingFlash::new();
+            let mut log = ReplayLog::load(&mut flash);
+            log.record(&mut flash, 0, 7).unwrap();
+            log.record(&mut flash, 2, 9).unwrap();
+            let mut timestamp = 0;
+            while log.next < RECORDS_PER_PAGE {
+                timestamp += 1000;
+                log.record(&mut flash, 1, timestamp).unwrap();
+            }
+            let before = marks(&ReplayLog::load(&mut flash));
+
+            // The reset comes after the next page is erased, and before its header is written
+            flash.budget = Some(budget);
+            assert!(log.record(&mut flash, 1, timestamp + 1_000_000_000).is_err());
+            flash.budget = None;
+            let restored = ReplayLog::load(&mut flash);
+            assert_eq!(marks(&restored), before, "budget {budget}");
+            assert_eq!(restored.generation, 1);
+        }
+    }
+}
@@ -1,10 +1,8 @@
-use crate::{decrypt_intermediate, flash, Integer, INTERMEDIATE_LOC, INTERMEDIATE_NUM, INTERMEDIATE_SIZE};
+use crate::{decrypt_intermediate, flash, DeviceKeys, Integer, INTERMEDIATE_LOC, INTERMEDIATE_NUM, INTERMEDIATE_SIZE};
+use crate::hw::Flash;
 use alloc::vec::Vec;
 use blake3::Hasher;
 use crypto_bigint::{Encoding, U512};
-use hal;
-use hal::flc::Flc;
-use crate::console::write_console;
 
 /// Indicate test keys to protect against tampering
 const FORWARD: u64 = 0x1f8c25d4b902e785;
@@ -22,10 +20,8 @@ pub struct SubStat {
 /// Loads subscription listings from flash memory
 pub fn get_subscriptions(subscriptions: &mut [Option<Subscription>; 9]) -> Vec<SubStat> {
     let mut ret: Vec<SubStat> = Vec::new();
-    for i in 1usize..9 {
-        let sub = subscriptions[i];
-        if sub.is_none() { continue; }
-        else { ret.push(SubStat { channel: sub.unwrap().channel, start: sub.unwrap().start, end: sub.unwrap().end }); }
+    for sub in subscriptions.iter().skip(1).flatten() {
+        ret.push(SubStat { channel: sub.channel, start: sub.start, end: sub.end });
     }
     ret
 }
@@ -39,7 +35,13 @@ pub struct Subscription {
     pub(crate) end: u64,
     pub(crate) channel: u32,
     pub(crate) location: usize,
-    pub(crate) curr_frame: u64
+    pub(crate) last_frame: Option<u64>
+}
+
+impl Default for Subscription {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Subscription {
@@ -51,13 +53,29 @@ impl Subscription {
             end: 0,
             channel: 0,
             location: 0,
-            curr_frame: 0
+            last_frame: None
+        }
+    }
+
+    /// Checks a frame's timestamp against the replay state of this channel
+    /// @param timestamp The timestamp of the incoming frame
+    /// @return Whether the timestamp is strictly after the last accepted frame
+    pub fn is_fresh(&self, timestamp: u64) -> bool {
+        match self.last_frame {
+            None => true,
+            Some(last) => timestamp > last
         }
     }
 
-    pub fn get_intermediate(&self, flc: &Flc, pos: usize, dir: u64) -> u128 {
+    /// Records a frame as accepted, so that it and any earlier frame can't be replayed
+    /// @param timestamp The timestamp of the accepted frame
+    pub fn accept(&mut self, timestamp: u64) {
+        self.last_frame = Some(timestamp);
+    }
+
+    pub fn get_intermediate<F: Flash>(&self, flash: &F, keys: &DeviceKeys, pos: usize, dir: u64) -> u128 {
         if self.location == 0 { // Emergency channel
-            let sub_bytes = include_bytes!("emergency.bin");
+            let sub_bytes = keys.emergency;
             let intermediate_pos = INTERMEDIATE_LOC as usize + pos * INTERMEDIATE_SIZE + (if dir == FORWARD {0} else {1024});
             return u128::from_be_bytes(sub_bytes[intermediate_pos..intermediate_pos+16].try_into().unwrap());
         }
@@ -68,17 +86,18 @@ impl Subscription {
             {self.location + (INTERMEDIATE_LOC as usize) + 1024 + pos * INTERMEDIATE_SIZE };
         let ref_location = ref_location as u32;
         let mut intermediate_buffer: [u8; INTERMEDIATE_SIZE] = [0; INTERMEDIATE_SIZE];
-        let _ = flash::read_bytes(flc, ref_location, &mut intermediate_buffer, INTERMEDIATE_SIZE);
+        let _ = flash::read_bytes(flash, ref_location, &mut intermediate_buffer, INTERMEDIATE_SIZE);
         u128::from_be_bytes(intermediate_buffer)
     }
 
     /// Decodes one part of the symmetric key for each frame.
     /// Encryption and decryption are symmetric, but the main difference is that different intermediates are used 
-    /// @param flc The flash controller, as always
+    /// @param flash The flash, as always
+    /// @param keys The device keys
     /// @param target The timestamp, possibly inverted
     /// @param dir Whether the key we're working with is forwards or backwards
     /// @return A part of the key we need
-    pub fn decode_side(&self, flc: &Flc, target: u64, dir: u64) -> U512 {
+    pub fn decode_side<F: Flash>(&self, flash: &F, keys: &DeviceKeys, target: u64, dir: u64) -> U512 {
         // The wackiness here is another way to avoid fault injection
         let pos = if dir == FORWARD {&self.forward_pos} else if dir == BACKWARD {&self.backward_pos} else {return U512::from(0u32)};
         let mut closest_pos: u64 = 0;
@@ -98,8 +117,8 @@ impl Subscription {
         }
 
         // Gets the intermediate from the closest index
-        let intermediate: u128 = self.get_intermediate(flc, closest_idx, dir);
-        let mut hashed_int = decrypt_intermediate(intermediate, self.channel);
+        let intermediate: u128 = self.get_intermediate(flash, keys, closest_idx, dir);
+        let mut hashed_int = decrypt_intermediate(keys, intermediate, self.channel);
         // The number of trailing zeros helps determine what iterations the value needs! Perfect.
         let mut idx = trailing_zeroes_special(closest_pos) - 1;
         loop {
@@ -130,23 +149,29 @@ impl Subscription {
         u128::from_be_bytes(res.try_into().unwrap())
     }
 
-    const BIG_BYTES: [u8; 64] =  [92, 244, 129, 255, 230, 241, 27, 64, 141, 102, 255, 242, 62, 90, 184,
+    pub(crate) const BIG_BYTES: [u8; 64] =  [92, 244, 129, 255, 230, 241, 27, 64, 141, 102, 255, 242, 62, 90, 184,
         39, 179, 61, 229, 42, 43, 60, 236, 180, 17, 81, 0, 19, 40, 237, 9, 31, 190, 96, 11, 35, 242, 31,
         191, 50, 123, 176, 19, 168, 38, 117, 144, 128, 85, 72, 55, 123, 175, 222, 187, 108, 70, 122, 249,
         95, 86, 175, 58, 231];
     
-    /// Manages the decoding process, combining the forward & backward keys with extra hash data and returning the decoded frame
-    /// @param flc The flash controller
-    /// @param frame The individual encrypted frame
+    /// Manages the decoding process, combining the forward & backward keys with extra hash data and decoding the frame in place
+    /// The keystream is read from the BLAKE3 XOF for as long as the frame is, so frames of any length take the same keys
+    /// @param flash The flash holding the subscription
+    /// @param keys The device keys
+    /// @param frame The individual encrypted frame, which is decoded in place
     /// @param timestamp The timestamp of the frame
-    /// @return Returns the decoded frame
-    pub fn decode(&self, flc: &Flc, frame: U512, timestamp: u64) -> U512 {
-        let forward = self.decode_side(flc, timestamp, FORWARD);
-        let backward = self.decode_side(flc, !timestamp, BACKWARD); // Technically passing in 2^64 - timestamp
+    pub fn decode<F: Flash>(&self, flash: &F, keys: &DeviceKeys, frame: &mut [u8], timestamp: u64) {
+        let forward = self.decode_side(flash, keys, timestamp, FORWARD);
+        let backward = self.decode_side(flash, keys, !timestamp, BACKWARD); // Technically passing in 2^64 - timestamp
         let guard:U512 = forward ^ backward;
+        let mut keystream = Hasher::new().update(&guard.to_be_bytes()).update(&Self::BIG_BYTES).finalize_xof();
         let mut product: [u8; 64] = [0u8; 64];
-        Hasher::new().update(&guard.to_be_bytes()).update(&Self::BIG_BYTES).finalize_xof().fill(&mut product);
-        frame ^ Integer::from_be_bytes(product)
+        for block in frame.chunks_mut(product.len()) {
+            keystream.fill(&mut product[..block.len()]);
+            for (byte, key) in block.iter_mut().zip(product) {
+                *byte ^= key;
+            }
+        }
     }
 }
 /// A helper function calculating how many iterations are required in decode_side.
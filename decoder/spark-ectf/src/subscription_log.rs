@@ -0,0 +1,505 @@
+use blake3::Hasher;
+use crate::error::DecoderError;
+use crate::hw::Flash;
+use crate::subscription::Subscription;
+use crate::{flash, SUBSCRIPTION_SIZE, SUB_LOC, SUB_SPACE};
+
+/// Pages the log rotates through, starting at SUB_LOC. Two subscriptions fit in a page, so this leaves room for
+/// every slot, the replacement for one of them and the page that compaction frees.
+pub const SUB_PAGES: u32 = 7;
+/// The most channels the log keeps a subscription for
+pub const LOG_CHANNELS: usize = 16;
+
+/// The page header and every record header are one 128-bit flash word
+const HEADER_SIZE: u32 = 16;
+/// Tag of the header in the first word of every formatted page
+const PAGE_MAGIC: u32 = 0x5350_5047;
+/// Tag of the header in front of every record
+const RECORD_MAGIC: u32 = 0x5350_5352;
+const ERASED: [u32; 4] = [0xFFFF_FFFF; 4];
+
+/// An append-only log of subscriptions across the pages reserved for them
+/// Subscribing appends a record holding the whole subscription, and unsubscribing appends a record with no body, so
+/// the latest committed record of a channel is the one that counts. Pages are filled in turn, and moving to the next page
+/// first copies what is still live out of the oldest page and erases it, so every page takes the same share of the erases.
+/// A record is only committed by the word written after it, which holds a MAC of the record keyed from the device
+/// keys, so an update cut short by a reset or a dropped link leaves the previous subscription of the channel in charge,
+/// and a record that was glitched or tampered with after the fact is caught and reported as corrupt. A corrupt record
+/// leaves its channel with no subscription at all, since falling back to an older one could undo a removal or a renewal.
+#[derive(Clone, Copy, Debug)]
+pub struct SubscriptionLog {
+    sequences: [Option<u64>; SUB_PAGES as usize],
+    head: u32,
+    next: u32,
+    channels: [u32; LOG_CHANNELS],
+    addresses: [u32; LOG_CHANNELS],
+    lengths: [u32; LOG_CHANNELS],
+    count: usize,
+    corrupt: [u32; LOG_CHANNELS],
+    corrupt_count: usize,
+    key: [u8; 32],
+}
+
+impl SubscriptionLog {
+    /// Restores the log from flash, finishing a compaction cut short by a reset and formatting it if no page holds a valid header
+    /// @param flash The flash holding the log
+    /// @param key The key of the MAC on every subscription, from DeviceKeys::storage_key
+    /// @return The restored log
+    pub fn load<F: Flash>(flash: &mut F, key: [u8; 32]) -> SubscriptionLog {
+        let mut log = SubscriptionLog {
+            sequences: [None; SUB_PAGES as usize],
+            head: 0,
+            next: SUB_SPACE,
+            channels: [0; LOG_CHANNELS],
+            addresses: [0; LOG_CHANNELS],
+            lengths: [0; LOG_CHANNELS],
+            count: 0,
+            corrupt: [0; LOG_CHANNELS],
+            corrupt_count: 0,
+            key,
+        };
+        for page in 0..SUB_PAGES {
+            log.sequences[page as usize] = match decode_header(read_word(flash, page_address(page))) {
+                Some([PAGE_MAGIC, high, low]) => Some(((high as u64) << 32) | low as u64),
+                _ => None,
+            };
+        }
+        let Some(head) = log.newest() else {
+            let _ = log.format(flash, 0, 0);
+            return log;
+        };
+
+        // The oldest page is only still there next to the head if a reset stopped it from being compacted,
+        // in which case the head holds nothing but copies from it, and the compaction starts over
+        let interrupted = log.sequences[next_page(head) as usize].is_some();
+        if interrupted {
+            log.sequences[head as usize] = None;
+        }
+        log.head = log.newest().unwrap_or(head);
+
+        // Replays the pages from the oldest to the newest, so later records take over from earlier ones
+        for i in 1..=SUB_PAGES {
+            let page = (log.head + i) % SUB_PAGES;
+            if log.sequences[page as usize].is_some() {
+                let end = log.scan(flash, page);
+                if page == log.head {
+                    log.next = end;
+                }
+            }
+        }
+        if interrupted {
+            let _ = log.advance(flash);
+        }
+        log
+    }
+
+    /// Gets where the subscription of a channel is kept
+    /// @param channel The channel ID
+    /// @return The address of the subscription, or None if the channel has none
+    pub fn find(&self, channel: u32) -> Option<u32> {
+        (0..self.count).find(|&i| self.channels[i] == channel).map(|i| self.addresses[i])
+    }
+
+    /// Gets every subscription in the log
+    /// @return The channel ID and address of each subscription
+    pub fn entries(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        (0..self.count).map(|i| (self.channels[i], self.addresses[i]))
+    }
+
+    /// Gets the channels whose latest record failed its MAC, which are left with no subscription
+    /// @return The channel IDs
+    pub fn corrupt(&self) -> &[u32] {
+        &self.corrupt[..self.corrupt_count]
+    }
+
+    /// Points the loaded subscriptions at where the log keeps them now, since compaction moves them around
+    /// @param subscriptions The mutable list of subscriptions, whose first entry is the emergency subscription
+    pub fn relocate(&self, subscriptions: &mut [Option<Subscription>; 9]) {
+        for sub in subscriptions.iter_mut().skip(1).flatten() {
+            if let Some(address) = self.find(sub.channel) {
+                sub.location = address as usize;
+            }
+        }
+    }
+
+    /// Appends a subscription, which takes over from any earlier one for its channel
+    /// @param flash The flash holding the log
+    /// @param channel The channel ID
+    /// @param body The subscription, at most SUBSCRIPTION_SIZE bytes
+    /// @return The address of the subscription, or the error that stopped the write
+    pub fn write<F: Flash>(&mut self, flash: &mut F, channel: u32, body: &[u8]) -> Result<u32, DecoderError> {
+        if body.is_empty() || body.len() > SUBSCRIPTION_SIZE {
+            return Err(DecoderError::BadLength);
+        }
+        self.reserve(flash, record_size(body.len() as u32))?;
+        let address = self.append(flash, channel, body)?;
+        self.remember(channel, address, body.len() as u32);
+        Ok(address)
+    }
+
+    /// Appends a record with no body, which removes the subscription of a channel
+    /// @param flash The flash holding the log
+    /// @param channel The channel ID
+    /// @return Either nothing, or the error that stopped the write
+    pub fn remove<F: Flash>(&mut self, flash: &mut F, channel: u32) -> Result<(), DecoderError> {
+        self.reserve(flash, record_size(0))?;
+        self.append(flash, channel, &[])?;
+        self.forget(channel);
+        Ok(())
+    }
+
+    /// Makes room for a record in the head page, moving on to the next page as often as it takes
+    /// @param flash The flash holding the log
+    /// @param size The size of the record
+    /// @return Either nothing, or the error that stopped the log from making room
+    fn reserve<F: Flash>(&mut self, flash: &mut F, size: u32) -> Result<(), DecoderError> {
+        // Compacting a page can fill the next one, but the log never holds enough to fill every page
+        for _ in 0..SUB_PAGES {
+            if self.next + size <= SUB_SPACE {
+                return Ok(());
+            }
+            self.advance(flash)?;
+        }
+        Err(DecoderError::StoreFull)
+    }
+
+    /// Moves the head to the next page, then frees the page after it by copying its live subscriptions into the head
+    /// @param flash The flash holding the log
+    /// @return Either nothing, or the flash error that stopped the compaction
+    fn advance<F: Flash>(&mut self, flash: &mut F) -> Result<(), DecoderError> {
+        let sequence = self.sequences[self.head as usize].map_or(0, |sequence| sequence.wrapping_add(1));
+        let page = next_page(self.head);
+        self.format(flash, page, sequence)?;
+
+        // The page after the head is the oldest one, and it has to be free before the head moves into it
+        let oldest = next_page(page);
+        if self.sequences[oldest as usize].is_none() {
+            return Ok(());
+        }
+        let mut body = [0u8; SUBSCRIPTION_SIZE];
+        for i in 0..self.count {
+            if page_of(self.addresses[i]) != oldest {
+                continue;
+            }
+            let length = self.lengths[i] as usize;
+            flash::read_bytes(flash, self.addresses[i], &mut body, length)?;
+            if self.next + record_size(length as u32) > SUB_SPACE {
+                return Err(DecoderError::StoreFull);
+            }
+            self.addresses[i] = self.append(flash, self.channels[i], &body[..length])?;
+        }
+        flash.erase_page(page_address(oldest))?;
+        self.sequences[oldest as usize] = None;
+        Ok(())
+    }
+
+    /// Writes a record at the end of the head page, which must have room for it
+    /// The header goes first, so the space of a record that is never committed can still be skipped over
+    /// @param flash The flash holding the log
+    /// @param channel The channel ID
+    /// @param body The subscription, or nothing to remove it
+    /// @return The address of the body, or the error that stopped the write
+    fn append<F: Flash>(&mut self, flash: &mut F, channel: u32, body: &[u8]) -> Result<u32, DecoderError> {
+        let address = page_address(self.head) + self.next;
+        self.next += record_size(body.len() as u32);
+        flash.write_128(address, &encode_header([RECORD_MAGIC, channel, body.len() as u32]))?;
+        flash::write_bytes(flash, address + HEADER_SIZE, body, body.len())?;
+
+        // Only what made it into the flash gets committed, and a removal is committed the same way
+        let written = mac(flash, &self.key, channel, address + HEADER_SIZE, body.len() as u32);
+        let mut hasher = Hasher::new_keyed(&self.key);
+        hasher.update(&channel.to_be_bytes()).update(&(body.len() as u32).to_be_bytes()).update(body);
+        if written != to_word(&hasher) {
+            return Err(DecoderError::SubscriptionLoad);
+        }
+        flash.write_128(commit_address(address, body.len() as u32), &written)?;
+        Ok(address + HEADER_SIZE)
+    }
+
+    /// Reads the records of a page into the table
+    /// @param flash The flash holding the log
+    /// @param page The index of the page in the log
+    /// @return Where the next record of the page goes, which is the end of the page if a torn record is in the way
+    fn scan<F: Flash>(&mut self, flash: &F, page: u32) -> u32 {
+        let mut offset = HEADER_SIZE;
+        while offset + HEADER_SIZE <= SUB_SPACE {
+            let address = page_address(page) + offset;
+            let Some([RECORD_MAGIC, channel, length]) = decode_header(read_word(flash, address)) else {
+                break;
+            };
+            if length as usize > SUBSCRIPTION_SIZE || offset + record_size(length) > SUB_SPACE {
+                break;
+            }
+            // A record that was never committed is skipped quietly, but one that doesn't match its MAC is corrupt
+            let commit = read_word(flash, commit_address(address, length));
+            if commit == mac(flash, &self.key, channel, address + HEADER_SIZE, length) {
+                if length == 0 {
+                    self.forget(channel);
+                } else {
+                    self.remember(channel, address + HEADER_SIZE, length);
+                }
+                self.set_corrupt(channel, false);
+            } else if commit != ERASED {
+                self.forget(channel);
+                self.set_corrupt(channel, true);
+            }
+            offset += record_size(length);
+        }
+        // A torn record can't be written over until the page is erased
+        if is_erased(flash, page, offset) { offset } else { SUB_SPACE }
+    }
+
+    /// Updates the table with the latest subscription of a channel, dropping it if the table is full
+    /// @param channel The channel ID
+    /// @param address The address of the subscription
+    /// @param length The length of the subscription
+    fn remember(&mut self, channel: u32, address: u32, length: u32) {
+        let pos = match (0..self.count).find(|&i| self.channels[i] == channel) {
+            Some(i) => i,
+            None if self.count < LOG_CHANNELS => {
+                self.count += 1;
+                self.count - 1
+            }
+            None => return,
+        };
+        self.channels[pos] = channel;
+        self.addresses[pos] = address;
+        self.lengths[pos] = length;
+    }
+
+    /// Marks or clears the latest subscription of a channel as corrupt
+    /// @param channel The channel ID
+    /// @param corrupt Whether the latest subscription failed its MAC
+    fn set_corrupt(&mut self, channel: u32, corrupt: bool) {
+        let pos = (0..self.corrupt_count).find(|&i| self.corrupt[i] == channel);
+        match pos {
+            None if corrupt && self.corrupt_count < LOG_CHANNELS => {
+                self.corrupt[self.corrupt_count] = channel;
+                self.corrupt_count += 1;
+            }
+            Some(i) if !corrupt => {
+                self.corrupt_count -= 1;
+                self.corrupt[i] = self.corrupt[self.corrupt_count];
+            }
+            _ => {}
+        }
+    }
+
+    /// Drops the subscription of a channel from the table
+    /// @param channel The channel ID
+    fn forget(&mut self, channel: u32) {
+        if let Some(i) = (0..self.count).find(|&i| self.channels[i] == channel) {
+            self.count -= 1;
+            self.channels[i] = self.channels[self.count];
+            self.addresses[i] = self.addresses[self.count];
+            self.lengths[i] = self.lengths[self.count];
+        }
+    }
+
+    /// Gets the page with the highest sequence number
+    /// @return The index of the page, or None if no page is formatted
+    fn newest(&self) -> Option<u32> {
+        (0..SUB_PAGES).filter(|&page| self.sequences[page as usize].is_some()).max_by_key(|&page| self.sequences[page as usize])
+    }
+
+    /// Erases a page and writes its header, making it the head of the log
+    /// The page is usually still blank from the compaction that freed it, and is then left as it is to spare an erase.
+    /// @param flash The flash holding the log
+    /// @param page The index of the page in the log
+    /// @param sequence The sequence number the page takes over with
+    /// @return Either nothing, or the flash error that stopped the format
+    fn format<F: Flash>(&mut self, flash: &mut F, page: u32, sequence: u64) -> Result<(), DecoderError> {
+        let address = page_address(page);
+        if !is_erased(flash, page, 0) {
+            flash.erase_page(address)?;
+        }
+        flash.write_128(address, &encode_header([PAGE_MAGIC, (sequence >> 32) as u32, sequence as u32]))?;
+        self.sequences[page as usize] = Some(sequence);
+        self.head = page;
+        self.next = HEADER_SIZE;
+        Ok(())
+    }
+}
+
+/// Gets the page after another one, wrapping around to the first
+/// @param page The index of the page in the log
+/// @return The index of the next page
+fn next_page(page: u32) -> u32 {
+    (page + 1) % SUB_PAGES
+}
+
+/// Gets the address of one page of the log
+/// @param page The index of the page in the log
+/// @return The address of the page in flash
+fn page_address(page: u32) -> u32 {
+    SUB_LOC + page * SUB_SPACE
+}
+
+/// Gets the page an address lies in
+/// @param address An address inside the log
+/// @return The index of the page in the log
+fn page_of(address: u32) -> u32 {
+    (address - SUB_LOC) / SUB_SPACE
+}
+
+/// Checks that the rest of a page reads as erased
+/// @param flash The flash holding the log
+/// @param page The index of the page in the log
+/// @param offset Where in the page to start
+/// @return Whether every word from the offset to the end of the page is erased
+fn is_erased<F: Flash>(flash: &F, page: u32, offset: u32) -> bool {
+    (offset..SUB_SPACE).step_by(HEADER_SIZE as usize).all(|offset| read_word(flash, page_address(page) + offset) == ERASED)
+}
+
+/// Gets how much of a page a record takes up
+/// Every subscription gets room for a whole one, so anything past its end reads back as erased
+/// @param length The length of the body of the record
+/// @return The size of the record, header and commit word included
+fn record_size(length: u32) -> u32 {
+    if length == 0 { 2 * HEADER_SIZE } else { 2 * HEADER_SIZE + SUBSCRIPTION_SIZE as u32 }
+}
+
+/// Gets where the commit word of a record goes, which is its last word
+/// @param address The address of the header of the record
+/// @param length The length of the body of the record
+/// @return The address of the commit word
+fn commit_address(address: u32, length: u32) -> u32 {
+    address + record_size(length) - HEADER_SIZE
+}
+
+/// Calculates the MAC of a record as it is in flash, using keyed BLAKE3 over its channel, length and body
+/// @param flash The flash holding the log
+/// @param key The key of the MAC
+/// @param channel The channel ID
+/// @param address The address of the body of the record
+/// @param length The length of the body of the record
+/// @return The first 128 bits of the MAC, as the commit word
+fn mac<F: Flash>(flash: &F, key: &[u8; 32], channel: u32, address: u32, length: u32) -> [u32; 4] {
+    let mut hasher = Hasher::new_keyed(key);
+    hasher.update(&channel.to_be_bytes()).update(&length.to_be_bytes());
+    for offset in (0..length).step_by(HEADER_SIZE as usize) {
+        let mut bytes = [0u8; HEADER_SIZE as usize];
+        for (chunk, word) in bytes.chunks_mut(4).zip(read_word(flash, address + offset)) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        hasher.update(&bytes[..(length - offset).min(HEADER_SIZE) as usize]);
+    }
+    to_word(&hasher)
+}
+
+/// Takes the first 128 bits of a hash as a flash word
+/// @param hasher The hasher holding everything that was hashed
+/// @return The start of the hash, as one word
+fn to_word(hasher: &Hasher) -> [u32; 4] {
+    let hash = hasher.finalize();
+    let mut word = [0u32; 4];
+    for (part, bytes) in word.iter_mut().zip(hash.as_bytes().chunks(4)) {
+        *part = u32::from_be_bytes(bytes.try_into().unwrap());
+    }
+    word
+}
+
+/// Reads one word from flash, treating unreadable words as erased
+/// @param flash The flash holding the log
+/// @param address The 128-bit aligned address of the word
+/// @return The raw word
+fn read_word<F: Flash>(flash: &F, address: u32) -> [u32; 4] {
+    flash.read_128(address).unwrap_or(ERASED)
+}
+
+/// Packs the fields of a header along with their check value
+/// @param fields The tag and two values of the header
+/// @return The raw header
+fn encode_header(fields: [u32; 3]) -> [u32; 4] {
+    [fields[0], fields[1], fields[2], check(fields)]
+}
+
+/// Unpacks a header, rejecting it if it was torn or corrupted
+/// @param header The raw header
+/// @return The tag and two values of the header, if it is intact
+fn decode_header(header: [u32; 4]) -> Option<[u32; 3]> {
+    let fields = [header[0], header[1], header[2]];
+    if header == ERASED || check(fields) != header[3] {
+        return None;
+    }
+    Some(fields)
+}
+
+/// Calculates the check value of a header using BLAKE3
+/// @param fields The tag and two values of the header
+/// @return The check value
+fn check(fields: [u32; 3]) -> u32 {
+    let mut hasher = Hasher::new();
+    for field in fields {
+        hasher.update(&field.to_be_bytes());
+    }
+    u32::from_be_bytes(hasher.finalize().as_bytes()[0..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::CountingFlash;
+
+    const KEY: [u8; 32] = [3; 32];
+
+    #[test]
+    fn blank_pages_are_not_erased_again() {
+        let mut flash = CountingFlash::new();
+        let mut log = SubscriptionLog::load(&mut flash, KEY);
+        assert_eq!(flash.erases, 0);
+
+        let body = [0x42; SUBSCRIPTION_SIZE];
+        for _ in 0..40 {
+            log.write(&mut flash, 1, &body).unwrap();
+        }
+        // Each page is erased once by the compaction that frees it, from when the log first wraps around onwards
+        let advances = log.sequences[log.head as usize].unwrap();
+        assert!(advances > 2 * SUB_PAGES as u64, "{advances} advances");
+        assert_eq!(flash.erases as u64, advances - (SUB_PAGES as u64 - 2));
+        assert_eq!(SubscriptionLog::load(&mut flash, KEY).find(1), log.find(1));
+    }
+
+    #[test]
+    fn removals_are_committed_with_a_mac() {
+        let mut flash = CountingFlash::new();
+        let mut log = SubscriptionLog::load(&mut flash, KEY);
+        log.write(&mut flash, 1, &[0x42; SUBSCRIPTION_SIZE]).unwrap();
+
+        // A removal cut short before its commit word leaves the subscription in place
+        let torn = page_address(log.head) + log.next;
+        flash.write_128(torn, &encode_header([RECORD_MAGIC, 1, 0])).unwrap();
+        let mut log = SubscriptionLog::load(&mut flash, KEY);
+        assert!(log.find(1).is_some());
+
+        let removal = page_address(log.head) + log.next;
+        log.remove(&mut flash, 1).unwrap();
+        let log = SubscriptionLog::load(&mut flash, KEY);
+        assert_eq!((log.find(1), log.corrupt()), (None, &[][..]));
+
+        // A commit word that doesn't match its removal is caught like any other
+        flash.write_128(commit_address(removal, 0), &[0; 4]).unwrap();
+        let log = SubscriptionLog::load(&mut flash, KEY);
+        assert_eq!((log.find(1), log.corrupt()), (None, &[1][..]));
+    }
+
+    #[test]
+    fn corrupt_latest_record_leaves_no_subscription() {
+        let mut flash = CountingFlash::new();
+        let mut log = SubscriptionLog::load(&mut flash, KEY);
+        log.write(&mut flash, 1, &[0x42; SUBSCRIPTION_SIZE]).unwrap();
+        log.write(&mut flash, 2, &[0x43; SUBSCRIPTION_SIZE]).unwrap();
+        let renewal = log.write(&mut flash, 1, &[0x44; SUBSCRIPTION_SIZE]).unwrap();
+
+        // The older subscription of the channel is still intact, but it doesn't come back
+        flash.write_128(renewal + 32, &[0; 4]).unwrap();
+        let mut log = SubscriptionLog::load(&mut flash, KEY);
+        assert_eq!((log.find(1), log.corrupt()), (None, &[1][..]));
+        assert!(log.find(2).is_some());
+
+        // Subscribing again clears it
+        let address = log.write(&mut flash, 1, &[0x45; SUBSCRIPTION_SIZE]).unwrap();
+        let log = SubscriptionLog::load(&mut flash, KEY);
+        assert_eq!((log.find(1), log.corrupt()), (Some(address), &[][..]));
+    }
+}
@@ -1,95 +1,44 @@
-use core::mem::MaybeUninit;
-use core::result::Result::Err;
-use hal::flc::{FlashError, Flc};
+use hal::flc::Flc;
 use hal::gcr::clocks::SystemClockResults;
 use hal::pac;
-
-// Core reference to our flash (initially uninitialized)
-const FLASH_HANDLE: MaybeUninit<hal::flc::Flc> = MaybeUninit::uninit();
-
-/**
- * Gets a reference to the flash controller
- * @output: An immutable flash controller reference
- */
-pub fn flash() -> &'static hal::flc::Flc {
-    unsafe { FLASH_HANDLE.assume_init_ref() }
-}
-
+use spark_ectf::hw::{Flash, FlashError};
 
 /// Creates the flash controller
 /// @param p A flash controller
 /// @param clks The system clock data
-pub fn init(flc: pac::Flc, clks: SystemClockResults) -> Flc {
-    Flc::new(flc, clks.sys_clk)
+pub fn init(flc: pac::Flc, clks: SystemClockResults) -> OnChipFlash {
+    OnChipFlash(Flc::new(flc, clks.sys_clk))
 }
-/// @param frm The address of the bytes to be read
-/// @param dst The reference to the data's destination
-/// @param len The size of the bytes to be read
-/// @return An error message or nothing on success
-pub fn read_bytes<'a>(flc: &Flc, frm: u32, dst: &mut [u8], len: usize) -> Result<(), &'a[u8]> {
-    // Checks that the slice has enough space
-    if dst.len() < len {
-        return Err(b"FlashError::LowSpace");
+
+/// The on-chip flash, as seen by the decoder logic
+pub struct OnChipFlash(Flc);
+
+impl Flash for OnChipFlash {
+    fn check_address(&self, address: u32) -> Result<(), FlashError> {
+        self.0.check_address(address).map_err(map_err)
     }
-        // Reads values 128 bits at a time
-    for i in 0..len / 16 {
-        // Verifies the address
-        if (dst.as_ptr() as i32) & 0b11 != 0 {
-            return Err(b"FlashError::InvalidAddress");
-        }
-        let addr_128_ptr = ((frm as usize) + i * 16) as u32;
-        // Security guarantee: We have checked the address already
-        unsafe {
-            // Collects the result and checks it for errors
-            let res = flc.read_128(addr_128_ptr);
-            if res.is_err() {
-                return Err(b"FlashError::ReadFailed");
-            }
-            // Assigns the result to the correct value
-            *((dst.as_ptr() as usize + i * 16) as *mut [u32; 4]) = res.unwrap();
-        }
-        }
 
-    Ok(())
-}
+    fn read_128(&self, address: u32) -> Result<[u32; 4], FlashError> {
+        self.0.read_128(address).map_err(map_err)
+    }
 
-/// Writes bytes to the flash
-/// @param dst A u32 representing the start address of the write location in flash memory
-/// @param from The slice of bytes being written
-/// @param len The length of the bytes that will be written
-/// @return Either nothing, or the error message
-pub fn write_bytes<'a>(flc: &Flc, dst: u32, from: &[u8], len: usize) -> Result<(), &'a [u8]> {
-    if from.len() < len {
-        return Err(b"FlashError::LowSpace");
+    fn write_128(&mut self, address: u32, data: &[u32; 4]) -> Result<(), FlashError> {
+        self.0.write_128(address, data).map_err(map_err)
     }
 
-    for i in 0usize..len / 16 {
-        // For 128-bit addresses
-        if (from.as_ptr() as i32) & 0b11 != 0 {
-            return Err(b"FlashError::InvalidAddress");
-        }
-        let addr_128_ptr = ((dst as usize) + i * 16) as u32;
-        // We have checked the address already
-        unsafe {
-            // Performs write
-            let bytes: [u32; 4]  = *((from.as_ptr() as usize + i * 16) as *const [u32; 4]);
-            let res = flc.write_128(addr_128_ptr, &bytes);
-            // Checks for errors
-            if res.is_err() {
-                return Err(map_err(res.unwrap_err()).as_bytes());
-            }
-        }
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError> {
+        // Security guarantee: The decoder logic only erases the subscription and replay pages
+        unsafe { self.0.erase_page(address).map_err(map_err) }
     }
-    Ok(())
 }
 
-/// Converts flash errors into string messages for write_bytes
+/// Converts the HAL's flash errors into the decoder's
 /// @param err A flash error
-/// @return The corresponding error message
-pub fn map_err(err: FlashError) -> &'static str {
+/// @return The corresponding decoder flash error
+fn map_err(err: hal::flc::FlashError) -> FlashError {
     match err {
-        FlashError::InvalidAddress => "FlashError::InvalidAddress",
-        FlashError::AccessViolation => "FlashError::AccessViolation",
-        FlashError::NeedsErase => "FlashError::NeedsErase"
+        hal::flc::FlashError::InvalidAddress => FlashError::InvalidAddress,
+        hal::flc::FlashError::AccessViolation => FlashError::AccessViolation,
+        hal::flc::FlashError::NeedsErase => FlashError::NeedsErase
     }
 }
@@ -13,6 +13,9 @@ from Crypto.PublicKey import ECC
 from Crypto.Signature import eddsa
 from Crypto.Hash import SHA512 
 
+# The largest frame a decoder can be built to take; see MAX_FRAME_SIZE in decoder/spark-ectf/src/lib.rs
+MAX_FRAME_SIZE = 1024
+
 # Hashes the value and the bit section, and then takes the lowest 128 bits
 def compress(n, section):
     compressed = int.from_bytes(blake3(section.to_bytes(1, byteorder="big")).update(n.to_bytes(16, byteorder="big")).digest()) & (2 ** 128 - 1)
@@ -62,7 +65,9 @@ class Encoder:
 
         :param channel: 16b unsigned channel number. Channel 0 is the emergency
             broadcast that must be decodable by all channels.
-        :param frame: Frame to encode. Max frame size is 64 bytes.
+        :param frame: Frame to encode. Max frame size is the MAX_FRAME_SIZE the Decoder
+            was built with (64 bytes by default), and frames decode to exactly the
+            bytes given.
         :param timestamp: 64b timestamp to use for encoding. **NOTE**: This value may
             have no relation to the current timestamp, so you should not compare it
             against the current time. The timestamp is guaranteed to strictly
@@ -90,15 +95,19 @@ class Encoder:
         forward = wind_encoder(self.cached_forward, extra)
         backward = wind_encoder(self.cached_backward, (end_of_time & ~self.cache_mask) - extra)
 
-        # Combines the keys with another hash and XOR
+        if len(frame) > MAX_FRAME_SIZE:
+            raise ValueError(f"Frame is {len(frame)} bytes, but the most is {MAX_FRAME_SIZE}")
+
+        # Combines the keys with another hash, whose output is stretched to the length of the frame
         guard_pre = forward ^ backward
         hasher = 0x5CF481FFE6F11B408D66FFF23E5AB827B33DE52A2B3CECB41151001328ED091FBE600B23F21FBF327BB013A8267590805548377BAFDEBB6C467AF95F56AF3AE7
-        hasher_1 = int.from_bytes(blake3(guard_pre.to_bytes(64, "big")).update(hasher.to_bytes(64, "big")).digest(64), "big")
-        guard = hasher_1
+        guard = blake3(guard_pre.to_bytes(64, "big")).update(hasher.to_bytes(64, "big")).digest(len(frame))
 
-        signature = eddsa.new(key=self.signer, mode='rfc8032', context=channel.to_bytes(4)).sign(SHA512.new(frame))
-        # Timestamp + signature + the frame XORed with the key combination
-        return struct.pack(">IQ", channel, timestamp) + signature + (guard ^ int.from_bytes(frame)).to_bytes(64)
+        # The length is signed along with the frame, so a packet can't be cut short
+        length = struct.pack(">H", len(frame))
+        signature = eddsa.new(key=self.signer, mode='rfc8032', context=channel.to_bytes(4)).sign(SHA512.new(length + frame))
+        # Timestamp + length + signature + the frame XORed with the key combination
+        return struct.pack(">IQ", channel, timestamp) + length + signature + bytes(a ^ b for a, b in zip(frame, guard))
 
 
 def main():
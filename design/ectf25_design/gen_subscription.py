@@ -9,6 +9,12 @@ from pathlib import Path
 import random
 from blake3 import blake3
 from Crypto.Cipher import AES
+from Crypto.PublicKey import ECC
+from Crypto.Signature import eddsa
+from Crypto.Hash import SHA512
+
+# The context subscriptions are signed with, which keeps their signatures apart from those on frames
+SUBSCRIPTION_CONTEXT = b"subscription"
 
 
 # Hashes it with Blake3
@@ -75,16 +81,23 @@ def pack_inter_positions(intermediates: dict):
     return _res
 
 # Stores all the metadata for the subscription, including the channels, the timestamps, and the intermediate positions and lengths.
-def pack_metadata(channel: int, start: int, end: int, forward_inters: dict, backward_inters: dict):
+# The ID of the decoder it is for comes last, so the decoder can turn down a subscription meant for another one.
+def pack_metadata(channel: int, device_id: int, start: int, end: int, forward_inters: dict, backward_inters: dict):
     _res = channel.to_bytes(4, byteorder='big') + \
         start.to_bytes(8, byteorder='big') + end.to_bytes(8, byteorder='big') + \
     	len(forward_inters).to_bytes(1, byteorder='big') + len(backward_inters).to_bytes(1, byteorder='big') + \
-        pack_inter_positions(forward_inters) + pack_inter_positions(backward_inters)
+        pack_inter_positions(forward_inters) + pack_inter_positions(backward_inters) + \
+        device_id.to_bytes(4, byteorder='big')
     
     for _ in range(1280 - len(_res)):
         _res += b"\x00"
     return _res
 
+# Signs the whole subscription, so the decoder can reject forged or spliced ones before it stores anything
+def sign(subscription: bytes, private_key: str):
+    signer = ECC.import_key(encoded=private_key, curve_name="Ed25519")
+    return eddsa.new(key=signer, mode='rfc8032', context=SUBSCRIPTION_CONTEXT).sign(SHA512.new(subscription))
+
 # Encrypts the data on the subscription as to avoid piracy
 def encrypt(data, seed):
     key = random.Random(seed).randbytes(32)
@@ -116,9 +129,10 @@ def gen_subscription(
     # Finally, we pack this like follows:
     secret = (secrets["systemsecret"] << 64) + (device_id << 32) + channel
 
-    # Pack the subscription. This will be sent to the decoder with ectf25.tv.subscribe
-    return pack_metadata(channel, start, end, forward_inters, backward_inters) + \
+    # Pack and sign the subscription. This will be sent to the decoder with ectf25.tv.subscribe
+    subscription = pack_metadata(channel, device_id, start, end, forward_inters, backward_inters) + \
         pack_intermediates(forward_inters, secret) + pack_intermediates(backward_inters, secret)
+    return subscription + sign(subscription, secrets["private"])
 
 def parse_args():
     """Define and parse the command line arguments
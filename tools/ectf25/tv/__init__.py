@@ -19,7 +19,8 @@ import time
 
 from loguru import logger
 
-from ectf25.utils.decoder import DecoderIntf
+from ectf25.utils.decoder import DecoderIntf, ErrorCode, FRAME_REJECTIONS
+from ectf25.utils.decoder import DecoderError as DecoderIntfError
 
 
 class DecoderError(Exception):
@@ -95,8 +96,15 @@ class TV:
                     # Get an encoded frame from the queue
                     encoded = self.to_decode.get_nowait()
 
-                    # Send the frame to be decoded
-                    decoded = self.decoder.decode(encoded)
+                    # Send the frame to be decoded, showing why the channel went dark
+                    # if the Decoder rejects it
+                    try:
+                        decoded = self.decoder.decode(encoded)
+                    except DecoderIntfError as e:
+                        if e.code not in FRAME_REJECTIONS:
+                            raise
+                        logger.warning(f"Frame rejected: {ErrorCode(e.code).name}")
+                        continue
 
                     # Print the frame
                     try:
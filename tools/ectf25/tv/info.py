@@ -0,0 +1,48 @@
+"""
+Reports what a Decoder is and what it can do, so it can be checked before it is
+sent subscriptions
+"""
+
+import argparse
+
+from loguru import logger
+
+from ectf25.utils.decoder import DecoderIntf
+
+
+def main():
+    # Define and parse command line arguments
+    parser = argparse.ArgumentParser(
+        prog="ectf25.tv.info",
+        description="Show the identity and capabilities of a Decoder",
+    )
+    parser.add_argument(
+        "port",
+        help="Serial port to the Decoder (see https://rules.ectf.mitre.org/2025/getting_started/boot_reference for platform-specific instructions), or socket://HOST:PORT for decoder-sim",
+    )
+    args = parser.parse_args()
+
+    # Open Decoder interface
+    decoder = DecoderIntf(args.port)
+
+    # Run the info command
+    info = decoder.info()
+
+    # Print the results
+    logger.info(f"Decoder ID: {info.decoder_id:#010x}")
+    logger.info(f"Firmware: {info.firmware_version} ({info.git_hash})")
+    logger.info(f"Protocol version: {info.protocol_version}")
+    logger.info(f"Channels: {', '.join(str(channel) for channel in info.channels)}")
+    logger.info(f"Free subscription slots: {info.free_slots} of {info.slots}")
+    logger.info(f"Max frame size: {info.max_frame_size}")
+    if info.corrupt:
+        logger.warning(
+            f"Dropped corrupt subscriptions for channels: "
+            f"{', '.join(str(channel) for channel in info.corrupt)}"
+        )
+
+    logger.success("Info successful")
+
+
+if __name__ == "__main__":
+    main()
@@ -25,7 +25,7 @@ def main():
     )
     parser.add_argument(
         "port",
-        help="Serial port to the Decoder (see https://rules.ectf.mitre.org/2025/getting_started/boot_reference for platform-specific instructions)",
+        help="Serial port to the Decoder (see https://rules.ectf.mitre.org/2025/getting_started/boot_reference for platform-specific instructions), or socket://HOST:PORT for decoder-sim",
     )
     args = parser.parse_args()
 
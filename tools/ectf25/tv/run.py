@@ -26,7 +26,7 @@ def main():
     parser.add_argument("sat_port", type=int, help="TCP port of the satellite")
     parser.add_argument(
         "dec_port",
-        help="Serial port to the Decoder (see https://rules.ectf.mitre.org/2025/getting_started/boot_reference for platform-specific instructions)",
+        help="Serial port to the Decoder (see https://rules.ectf.mitre.org/2025/getting_started/boot_reference for platform-specific instructions), or socket://HOST:PORT for decoder-sim",
     )
     parser.add_argument(
         "--baud", type=int, default=115200, help="Baud rate of the serial port"
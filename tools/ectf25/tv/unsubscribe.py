@@ -0,0 +1,36 @@
+"""
+Removes a Decoder's subscription to a channel, so the Decoder can be handed to
+another customer
+"""
+
+import argparse
+
+from loguru import logger
+
+from ectf25.utils.decoder import DecoderIntf
+
+
+def main():
+    # Define and parse command line arguments
+    parser = argparse.ArgumentParser(
+        prog="ectf25.tv.unsubscribe",
+        description="Remove a Decoder's subscription to a channel",
+    )
+    parser.add_argument("channel", type=int, help="Channel to unsubscribe from")
+    parser.add_argument(
+        "port",
+        help="Serial port to the Decoder (see https://rules.ectf.mitre.org/2025/getting_started/boot_reference for platform-specific instructions), or socket://HOST:PORT for decoder-sim",
+    )
+    args = parser.parse_args()
+
+    # Open Decoder interface
+    decoder = DecoderIntf(args.port)
+
+    # Run unsubscribe command
+    decoder.unsubscribe(args.channel)
+
+    logger.success("Unsubscribe successful")
+
+
+if __name__ == "__main__":
+    main()
@@ -13,27 +13,60 @@ Copyright: Copyright (c) 2025 The MITRE Corporation
 from dataclasses import dataclass
 from enum import IntEnum
 import struct
-from typing import Optional, Iterator
+import zlib
+from typing import Optional, Iterable, Iterator
 
 from loguru import logger
-from serial import Serial
+from serial import serial_for_url
 
 MAGIC = b"%"
 BLOCK_LEN = 256
 
+# The version of the protocol these tools speak; see PROTOCOL_VERSION in decoder/spark-ectf/src/protocol.rs
+PROTOCOL_VERSION = 9
+
 
 class Opcode(IntEnum):
     """Enum class for use in device output processing."""
 
     DECODE = 0x44  # D
+    BATCH_DECODE = 0x42  # B
     SUBSCRIBE = 0x53  # S
+    UNSUBSCRIBE = 0x55  # U
     LIST = 0x4C  # L
+    INFO = 0x49  # I
+    STREAM = 0x54  # T
+    OPTIONS = 0x4F  # O
+    HELLO = 0x48  # H
+    PING = 0x50  # P
     ACK = 0x41  # A
+    NAK = 0x4E  # N
     DEBUG = 0x47  # G
     ERROR = 0x45  # E
 
 
-NACK_MSGS = {Opcode.DEBUG, Opcode.ACK}
+NACK_MSGS = {Opcode.DEBUG, Opcode.ACK, Opcode.NAK}
+
+# The most frames a BATCH_DECODE message may carry
+MAX_BATCH = 16
+
+# The largest streaming window a Decoder grants
+MAX_STREAM_WINDOW = 32
+
+# The OPTIONS flag that has a CRC-32 follow every block sent to the Decoder
+LINK_CRC = 0x01
+
+# The HELLO feature bits
+FEATURE_BATCH = 0x01
+FEATURE_CRC = 0x02
+FEATURE_STREAM = 0x04
+
+# The largest payload a PING message may carry
+MAX_PING = 256
+
+# How long to wait for the Decoder to answer HELLO, in seconds. Firmware older than
+# HELLO never answers it.
+HELLO_TIMEOUT = 5
 
 
 @dataclass
@@ -81,20 +114,155 @@ class Message:
         """Pack the Message into bytes"""
         return self.hdr.pack() + self.body
 
-    def packets(self) -> Iterator[bytes]:
+    def packets(self, crc: bool = False) -> Iterator[bytes]:
         """An iterator that chunks the message into blocks to send to the Decoder. An
-        ACK is expected from the Decoder after each block"""
+        ACK is expected from the Decoder after each block
+
+        :param crc: Whether to follow each block with its CRC-32
+        """
         yield self.hdr.pack()
         for i in range(0, len(self.body), BLOCK_LEN):
-            yield self.body[i : i + BLOCK_LEN]
+            block = self.body[i : i + BLOCK_LEN]
+            if crc:
+                block += struct.pack("<I", zlib.crc32(block))
+            yield block
 
     def is_ack(self) -> bool:
         """Returns whether the message is an ACK"""
         return self.opcode == Opcode.ACK
 
 
+@dataclass
+class DecoderInfo:
+    """What a Decoder reports about itself; see InfoRecord in decoder/spark-ectf/src/protocol.rs"""
+
+    FORMAT = "<HI3s20sBBHB16IH"
+
+    protocol_version: int
+    decoder_id: int
+    firmware_version: str
+    git_hash: str
+    slots: int
+    free_slots: int
+    max_frame_size: int
+    channels: list[int]
+    corrupt: list[int]
+
+    @classmethod
+    def parse(cls, body: bytes) -> "DecoderInfo":
+        """Parse the body of an INFO response
+
+        :param body: Body of the response
+        :returns: The Decoder information
+        """
+        fields = struct.unpack(cls.FORMAT, body)
+        (protocol, decoder_id, version, git_hash, slots, free, frame, nchannels) = fields[:8]
+        channels, corrupt = fields[8:24], fields[24]
+        return cls(
+            protocol,
+            decoder_id,
+            ".".join(str(part) for part in version),
+            git_hash.hex(),
+            slots,
+            free,
+            frame,
+            list(channels[:nchannels]),
+            [channel for i, channel in enumerate(channels) if corrupt & (1 << i)],
+        )
+
+
+@dataclass
+class DecoderHello:
+    """What a Decoder says in answer to HELLO; see HelloRecord in decoder/spark-ectf/src/protocol.rs"""
+
+    FORMAT = "<HHI"
+
+    min_version: int
+    max_version: int
+    features: int
+
+    @classmethod
+    def parse(cls, body: bytes) -> "DecoderHello":
+        """Parse the body of a HELLO response
+
+        :param body: Body of the response
+        :returns: The versions and features of the Decoder
+        """
+        return cls(*struct.unpack(cls.FORMAT, body))
+
+
+@dataclass
+class LinkStats:
+    """What a Decoder has seen on the link since it booted; see LinkStats in
+    decoder/spark-ectf/src/protocol.rs"""
+
+    FORMAT = "<III"
+
+    received: int
+    framing_errors: int
+    resyncs: int
+
+    @classmethod
+    def parse(cls, body: bytes) -> "LinkStats":
+        """Parse the counters at the start of a PING response
+
+        :param body: Body of the response, or at least its start
+        :returns: The link counters
+        """
+        return cls(*struct.unpack_from(cls.FORMAT, body))
+
+
+class ErrorCode(IntEnum):
+    """Codes the Decoder sends at the start of an ERROR message. They are stable, so
+    they can be acted on; see decoder/spark-ectf/src/error.rs"""
+
+    FLASH_INVALID_ADDRESS = 0x0101
+    FLASH_ACCESS_VIOLATION = 0x0102
+    FLASH_NEEDS_ERASE = 0x0103
+    BUFFER_TOO_SMALL = 0x0104
+    STORE_FULL = 0x0105
+    REPLAY_LOG_FULL = 0x0106
+    EMERGENCY_SUBSCRIPTION = 0x0201
+    UNKNOWN_CHANNEL = 0x0202
+    SUBSCRIPTION_LOAD = 0x0203
+    NOT_SUBSCRIBED = 0x0204
+    SUBSCRIPTION_EXPIRED = 0x0205
+    BEFORE_START = 0x0206
+    REPLAYED = 0x0207
+    WRONG_DEVICE = 0x0208
+    TABLE_FULL = 0x0209
+    UNKNOWN_OPCODE = 0x0301
+    BAD_LENGTH = 0x0302
+    TIMEOUT = 0x0303
+    BAD_CHECKSUM = 0x0304
+    UNSUPPORTED_VERSION = 0x0305
+    SIGNATURE_FAILED = 0x0401
+    BAD_VERIFICATION_KEY = 0x0402
+    SUBSCRIPTION_SIGNATURE = 0x0403
+    INTEGRITY_CHECK = 0x0501
+    PANIC = 0x0601
+
+
+# Codes the Decoder answers a DECODE with when it rejects that one frame
+FRAME_REJECTIONS = {
+    ErrorCode.NOT_SUBSCRIBED,
+    ErrorCode.BEFORE_START,
+    ErrorCode.SUBSCRIPTION_EXPIRED,
+    ErrorCode.REPLAYED,
+    ErrorCode.SIGNATURE_FAILED,
+    ErrorCode.INTEGRITY_CHECK,
+}
+
+
 class DecoderError(Exception):
-    pass
+    """Error talking to the Decoder, or an error reported by it
+
+    :param code: The code of the ERROR message, if the Decoder sent one
+    """
+
+    def __init__(self, message: str, code: Optional[int] = None):
+        super().__init__(message)
+        self.code = code
 
 
 class DecoderIntf:
@@ -107,24 +275,84 @@ class DecoderIntf:
 
     def __init__(self, port, **serial_kwargs):
         """
-        :param port: Serial port to the Decoder
+        :param port: Serial port to the Decoder, or a pySerial URL such as
+            socket://127.0.0.1:2025 for the decoder simulator
         :param serial_kwargs: Args to pass to the serial interface construction
         """
-        self.ser = Serial(baudrate=115200, **serial_kwargs)
-        self.ser.port = port
+        self.ser = serial_for_url(port, do_not_open=True, baudrate=115200, **serial_kwargs)
         self.stream = b""
+        # The streaming window granted by the Decoder, or 0 in lock-step mode
+        self.window = 0
+        # Whether blocks sent to the Decoder carry a CRC-32
+        self.crc = False
+        # The FEATURE bits the Decoder reported when the connection was opened
+        self.features = 0
 
     def _open(self):
-        """Open the serial connection if not already opened"""
+        """Open the serial connection if not already opened, and check that the Decoder
+        speaks the same protocol"""
         if not self.ser.is_open:
             self.ser.open()
+            self.features = self.hello().features
+
+    def hello(self) -> DecoderHello:
+        """Tell the Decoder which protocol version these tools speak
+
+        :returns: The versions and features of the Decoder
+        :raises DecoderError: The Decoder doesn't speak this version, or didn't answer
+        """
+        timeout, self.ser.timeout = self.ser.timeout, HELLO_TIMEOUT
+        try:
+            # send hello message
+            msg = Message(Opcode.HELLO, struct.pack("<H", PROTOCOL_VERSION))
+            self.send_msg(msg)
+
+            # receive response
+            resp = self.get_msg()
+        except DecoderError as e:
+            if e.code == ErrorCode.UNSUPPORTED_VERSION:
+                raise DecoderError(
+                    f"Decoder doesn't speak protocol version {PROTOCOL_VERSION}", e.code
+                ) from e
+            if e.code is None:
+                raise DecoderError(
+                    f"Decoder didn't answer HELLO, so its firmware is likely older than "
+                    f"protocol version {PROTOCOL_VERSION}: {e}"
+                ) from e
+            raise
+        finally:
+            self.ser.timeout = timeout
+        if resp.opcode != Opcode.HELLO or len(resp.body) != struct.calcsize(
+            DecoderHello.FORMAT
+        ):
+            raise DecoderError(f"Bad hello response {resp}")
+        hello = DecoderHello.parse(resp.body)
+        logger.debug(f"Decoder speaks protocol versions {hello.min_version} to {hello.max_version}")
+        if not hello.min_version <= PROTOCOL_VERSION <= hello.max_version:
+            raise DecoderError(
+                f"Decoder speaks protocol versions {hello.min_version} to "
+                f"{hello.max_version}, not {PROTOCOL_VERSION}"
+            )
+        return hello
+
+    def _require(self, feature: int, name: str):
+        """Check that the Decoder has an optional feature
+
+        :param feature: The FEATURE bit
+        :param name: What the feature is called, for the error
+        :raises DecoderError: The Decoder doesn't have the feature
+        """
+        self._open()
+        if not self.features & feature:
+            raise DecoderError(f"Decoder doesn't support {name}")
 
     def decode(self, frame: bytes) -> bytes:
         """Decode a frame
 
         :param frame: An encoded frame to be decoded
         :returns: The decoded frame
-        :raises DecoderError: Error on decode failure
+        :raises DecoderError: Error on decode failure. If the Decoder rejected the
+            frame, its code is one of FRAME_REJECTIONS
         """
         # send decode message
         msg = Message(Opcode.DECODE, frame)
@@ -136,6 +364,36 @@ class DecoderIntf:
             raise DecoderError(f"Bad decode response {resp}")
         return resp.body
 
+    def decode_batch(self, frames: list[bytes]) -> list[tuple[int, bytes]]:
+        """Decode several frames with one message, in order
+
+        :param frames: Up to MAX_BATCH encoded frames to be decoded
+        :returns: A status and decoded frame for each frame. The status is 0 if the
+            frame was decoded, or else the ErrorCode it was rejected with
+        :raises DecoderError: Error on batch decode failure
+        """
+        self._require(FEATURE_BATCH, "BATCH_DECODE")
+
+        # send batch decode message
+        msg = Message(Opcode.BATCH_DECODE, b"".join(frames))
+        self.send_msg(msg)
+
+        # receive response
+        resp = self.get_msg()
+        if resp.opcode != Opcode.BATCH_DECODE:
+            raise DecoderError(f"Bad batch decode response {resp}")
+
+        # unpack the results, which each take only as much room as their frame
+        results = []
+        i = 0
+        while i + 4 <= len(resp.body):
+            status, length = struct.unpack("<HH", resp.body[i : i + 4])
+            results.append((status, resp.body[i + 4 : i + 4 + length]))
+            i += 4 + length
+        if i != len(resp.body) or len(results) != len(frames):
+            raise DecoderError(f"Bad batch decode response {resp}")
+        return results
+
     def subscribe(self, subscription: bytes):
         """Subscribe the Decoder to a new subscription
 
@@ -152,6 +410,154 @@ class DecoderIntf:
         if resp != Message(Opcode.SUBSCRIBE, b""):
             raise DecoderError(f"Bad subscribe response {resp}")
 
+    def unsubscribe(self, channel: int):
+        """Remove the Decoder's subscription to a channel
+
+        :param channel: Channel to remove the subscription for
+        :raises DecoderError: Error on unsubscribe failure
+        """
+        # send unsubscribe message
+        msg = Message(Opcode.UNSUBSCRIBE, struct.pack(">I", channel))
+        self.send_msg(msg)
+
+        # receive response
+        resp = self.get_msg()
+        if resp != Message(Opcode.UNSUBSCRIBE, b""):
+            raise DecoderError(f"Bad unsubscribe response {resp}")
+
+    def start_streaming(self, window: int = MAX_STREAM_WINDOW) -> int:
+        """Switch the link to streaming mode, where nothing is ACKed and up to a
+        window of DECODE messages can wait for an answer at once
+
+        :param window: The window to ask for
+        :returns: The window granted by the Decoder
+        :raises DecoderError: Error on stream failure
+        """
+        self._require(FEATURE_STREAM, "streaming")
+
+        # send stream message
+        msg = Message(Opcode.STREAM, struct.pack("<BH", 1, window))
+        self.send_msg(msg)
+
+        # receive response
+        resp = self.get_msg()
+        if resp.opcode != Opcode.STREAM or len(resp.body) != 3:
+            raise DecoderError(f"Bad stream response {resp}")
+        mode, self.window = struct.unpack("<BH", resp.body)
+        if mode != 1 or self.window == 0:
+            raise DecoderError(f"Bad stream response {resp}")
+        return self.window
+
+    def stop_streaming(self):
+        """Switch the link back to lock-step mode
+
+        :raises DecoderError: Error on stream failure
+        """
+        # send stream message
+        msg = Message(Opcode.STREAM, struct.pack("<BH", 0, 0))
+        self.send_msg(msg)
+
+        # receive response, the last one that isn't ACKed
+        resp = self.get_msg()
+        self.window = 0
+        if resp != Message(Opcode.STREAM, struct.pack("<BH", 0, 0)):
+            raise DecoderError(f"Bad stream response {resp}")
+
+    def decode_stream(self, frames: Iterable[bytes]) -> Iterator[tuple[int, bytes]]:
+        """Decode frames in streaming mode, keeping the granted window of them waiting
+        for an answer
+
+        :param frames: Encoded frames to be decoded
+        :returns: A status and decoded frame for each frame, in order. The status is 0
+            if the frame was decoded, or else the ErrorCode it was rejected with
+        :raises DecoderError: Error on decode failure, or if not streaming
+        """
+        if not self.window:
+            raise DecoderError("Streaming mode is not on")
+        waiting = 0
+        for frame in frames:
+            # Each answer hands a credit back
+            if waiting == self.window:
+                yield self._get_stream_result()
+                waiting -= 1
+            self.send_msg(Message(Opcode.DECODE, frame))
+            waiting += 1
+        for _ in range(waiting):
+            yield self._get_stream_result()
+
+    def _get_stream_result(self) -> tuple[int, bytes]:
+        """Get the answer to a DECODE sent in streaming mode
+
+        :returns: The status and decoded frame
+        :raises DecoderError: If unexpected behavior encountered
+        """
+        try:
+            resp = self.get_msg()
+        except DecoderError as e:
+            if e.code is None:
+                raise
+            return e.code, b""
+        if resp.opcode != Opcode.DECODE:
+            raise DecoderError(f"Bad decode response {resp}")
+        return 0, resp.body
+
+    def set_options(self, flags: int) -> int:
+        """Set the link options, which apply from the next message on
+
+        :param flags: The OPTIONS flags to turn on, such as LINK_CRC
+        :returns: The flags the Decoder turned on
+        :raises DecoderError: Error on options failure
+        """
+        if flags & LINK_CRC:
+            self._require(FEATURE_CRC, "LINK_CRC")
+
+        # send options message
+        msg = Message(Opcode.OPTIONS, bytes([flags]))
+        self.send_msg(msg)
+
+        # receive response
+        resp = self.get_msg()
+        if resp.opcode != Opcode.OPTIONS or len(resp.body) != 1:
+            raise DecoderError(f"Bad options response {resp}")
+        self.crc = bool(resp.body[0] & LINK_CRC)
+        return resp.body[0]
+
+    def ping(self, payload: bytes = b"") -> LinkStats:
+        """Check that the Decoder is answering, such as between bursts of frames
+
+        :param payload: Up to MAX_PING bytes the Decoder should send back
+        :returns: What the Decoder has seen on the link since it booted
+        :raises DecoderError: Error on ping failure, or if the payload came back wrong
+        """
+        # send ping message
+        msg = Message(Opcode.PING, payload)
+        self.send_msg(msg)
+
+        # receive response
+        resp = self.get_msg()
+        size = struct.calcsize(LinkStats.FORMAT)
+        if resp.opcode != Opcode.PING or resp.body[size:] != payload:
+            raise DecoderError(f"Bad ping response {resp}")
+        return LinkStats.parse(resp.body)
+
+    def info(self) -> DecoderInfo:
+        """Ask the Decoder what it is and what it can do
+
+        :returns: The Decoder information
+        :raises DecoderError: Error on info failure
+        """
+        # send info message
+        msg = Message(Opcode.INFO, b"")
+        self.send_msg(msg)
+
+        # receive response
+        resp = self.get_msg()
+        if resp.opcode != Opcode.INFO or len(resp.body) != struct.calcsize(
+            DecoderInfo.FORMAT
+        ):
+            raise DecoderError(f"Bad info response {resp}")
+        return DecoderInfo.parse(resp.body)
+
     def list(self) -> list[tuple[int, int, int]]:
         """List the subscribed channels of a Decoder
 
@@ -196,15 +602,20 @@ class DecoderIntf:
         self._open()
         self.ser.write(self.ACK.pack())
 
-    def get_ack(self):
+    def get_ack(self) -> bool:
         """Get an expected ACK from the Decoder
 
+        :returns: True for an ACK, or False for a NAK asking for the last block again
         :raises DecoderError: Non-ACK response was received (other than DEBUGs)
         """
         msg = self.get_msg()
+        if msg == Message(Opcode.NAK, b""):
+            logger.warning("Got NAK, sending the block again")
+            return False
         if msg != self.ACK:
             logger.error(f"Got bad ACK {msg}")
             raise DecoderError(f"Got bad ACK {msg}")
+        return True
 
     def try_parse(self) -> Optional[MessageHdr]:
         """Try to parse the input stream into a MessageHdr
@@ -227,18 +638,23 @@ class DecoderIntf:
         self._open()
         while (hdr := self.try_parse()) is None:
             b = self.ser.read(1)
+            if not b:
+                raise DecoderError("Timed out waiting for the Decoder")
             self.stream += b
-        # Don't ACK an ACK or a debug message
-        if hdr.opcode not in NACK_MSGS:
+        # Don't ACK an ACK or a debug message, or anything while streaming
+        if hdr.opcode not in NACK_MSGS and not self.window:
             self.send_ack()
         remaining = hdr.len
         body = b""
         while remaining > 0:
             block = b""
             while block_remaining := min(BLOCK_LEN, remaining) - len(block):
-                block += self.ser.read(block_remaining)
-            # Don't ACK an ACK or a debug message
-            if hdr.opcode not in NACK_MSGS:
+                data = self.ser.read(block_remaining)
+                if not data:
+                    raise DecoderError("Timed out waiting for the Decoder")
+                block += data
+            # Don't ACK an ACK or a debug message, or anything while streaming
+            if hdr.opcode not in NACK_MSGS and not self.window:
                 self.send_ack()
             logger.debug(f"Read block {repr(block)}")
             body += block
@@ -256,7 +672,12 @@ class DecoderIntf:
         while True:
             msg = self.get_raw_msg()
             if msg.opcode == Opcode.ERROR:
-                raise DecoderError(f"Decoder returned ERROR: {repr(msg.body)}")
+                if len(msg.body) < 2:
+                    raise DecoderError(f"Decoder returned ERROR: {repr(msg.body)}")
+                code = struct.unpack("<H", msg.body[:2])[0]
+                raise DecoderError(
+                    f"Decoder returned ERROR {code:#06x}: {repr(msg.body[2:])}", code
+                )
             if msg.opcode != Opcode.DEBUG:
                 return msg
             logger.info(f"Got DEBUG: {repr(msg.body)}")
@@ -268,7 +689,13 @@ class DecoderIntf:
         :raises DecoderError: If unexpected behavior or ERROR message encountered
         """
         self._open()
-        for packet in msg.packets():
+        if self.window:
+            logger.debug(f"Streaming message {msg}")
+            self.ser.write(msg.pack())
+            return
+        for packet in msg.packets(self.crc):
             logger.debug(f"Sending packet {packet}")
             self.ser.write(packet)
-            self.get_ack()
+            # The Decoder gives up with an ERROR if the block keeps getting corrupted
+            while not self.get_ack():
+                self.ser.write(packet)
@@ -160,7 +160,7 @@ def parse_args():
     decode_parser.set_defaults(threshold=100.0)
     decode_parser.add_argument(
         "port",
-        help="Serial port to the Decoder (See https://rules.ectf.mitre.org/2025/getting_started/boot_reference for platform-specific instructions)",
+        help="Serial port to the Decoder (See https://rules.ectf.mitre.org/2025/getting_started/boot_reference for platform-specific instructions), or socket://HOST:PORT for decoder-sim",
     )
     decode_parser.add_argument(
         "frames",
@@ -129,7 +129,7 @@ def parse_args():
         "--port",
         "-p",
         default=None,
-        help="Serial port to the Decoder (See https://rules.ectf.mitre.org/2025/getting_started/boot_reference for platform-specific instructions)",
+        help="Serial port to the Decoder (See https://rules.ectf.mitre.org/2025/getting_started/boot_reference for platform-specific instructions), or socket://HOST:PORT for decoder-sim",
     )
     parser.add_argument(
         "--delay", "-d", type=float, default=0, help="Delay after frame decoding"